@@ -1,38 +1,165 @@
+use std::{net::IpAddr, path::PathBuf};
+
 use clap::{Args, Parser, Subcommand};
-use ipnetwork::Ipv4Network;
 use log::LevelFilter;
 
+use crate::{
+    config::{Config, DEFAULT_METRIC, DEFAULT_POLL_INTERVAL_SECS, NetworkingMode, WSL_OWNER},
+    error::Error,
+    logging::{DEFAULT_LOG_MAX_AGE_DAYS, DEFAULT_LOG_MAX_FILES, DEFAULT_LOG_MAX_SIZE_MB, LogFormat},
+    output::OutputFormat,
+    routes::RouteSpec,
+};
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Print the result of the command as text or as a JSON document.
+    #[clap(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
 }
 
 #[derive(Args, Debug)]
 pub struct RunArgs {
+    /// Path of a JSON configuration file. When specified, the other options are read from this file.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "wsl_interface",
+            "routes",
+            "networking_mode",
+            "wsl_guest_address",
+            "vm_owner",
+            "vm_name",
+            "log_level",
+            "log_format",
+            "log_dir",
+            "log_max_size",
+            "log_max_files",
+            "log_max_age",
+            "keep_routes_on_stop",
+            "poll_interval",
+            "metric",
+        ]
+    )]
+    pub config: Option<PathBuf>,
+
     /// The name of the WSL network interface. If specified this interface will be used instead of auto detecting it.
     #[clap(long)]
     pub wsl_interface: Option<String>,
 
-    /// Route in the format IP/MASK. This argument can be repeated. For example: -r 10.1.0.0/16 -r 10.96.0.0/12
+    /// Route in the format IP/MASK[,metric=METRIC][,via=IP]. This argument can be repeated. For example: -r 10.1.0.0/16 -r 10.96.0.0/12,metric=5 -r fd00:10:96::/112
     #[clap(
         action(clap::ArgAction::Append),
         long("route"),
         short,
-        required(true),
+        required_unless_present("config"),
         value_parser  = validate_route,
         value_name = "ROUTE"
     )]
-    pub routes: Vec<Ipv4Network>,
+    pub routes: Vec<RouteSpec>,
+
+    /// How WSL is networked. By default the networkingMode of the users' .wslconfig files is used.
+    #[clap(long, value_enum, default_value_t = NetworkingMode::Auto)]
+    pub networking_mode: NetworkingMode,
+
+    /// Address of the WSL VM to send routes to in mirrored mode, such as an address added to its interface.
+    #[clap(long, value_name = "IP")]
+    pub wsl_guest_address: Option<IpAddr>,
+
+    /// Owner of the HCS compute systems to route through, as listed by `hcsdiag list`. For example DockerDesktopVM.
+    #[clap(long, default_value = WSL_OWNER)]
+    pub vm_owner: String,
+
+    /// Only route through the compute system of the owner with this name or id.
+    #[clap(long)]
+    pub vm_name: Option<String>,
 
     #[clap(long, default_value("Info"))]
-    pub log_level: LevelFilter 
+    pub log_level: LevelFilter,
+
+    /// Write the log as text lines or as one JSON object per line.
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Directory of the service log. Defaults to %ProgramData%\route2wsl\logs.
+    #[clap(long, value_name = "PATH")]
+    pub log_dir: Option<PathBuf>,
+
+    /// Megabytes the log file grows to before it is rotated.
+    #[clap(long, default_value_t = DEFAULT_LOG_MAX_SIZE_MB, value_parser = clap::value_parser!(u64).range(1..))]
+    pub log_max_size: u64,
+
+    /// Rotated log files that are kept.
+    #[clap(long, default_value_t = DEFAULT_LOG_MAX_FILES)]
+    pub log_max_files: u32,
+
+    /// Days after which the log file is rotated, whatever its size. 0 only rotates by size.
+    #[clap(long, default_value_t = DEFAULT_LOG_MAX_AGE_DAYS)]
+    pub log_max_age: u64,
+
+    /// Leave the routes in the routing table when the service stops. They are still removed on uninstall.
+    #[clap(long)]
+    pub keep_routes_on_stop: bool,
+
+    /// Seconds between routing table checks when no network change is notified.
+    #[clap(long, default_value_t = DEFAULT_POLL_INTERVAL_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+    pub poll_interval: u64,
+
+    /// Metric of the routes added to the routing table.
+    #[clap(long, default_value_t = DEFAULT_METRIC)]
+    pub metric: u32,
+}
+
+impl RunArgs {
+    /// Loads the configuration file if one was specified, otherwise builds the configuration from the options.
+    pub fn to_config(&self) -> Result<Config, Error> {
+        match &self.config {
+            Some(path) => Config::load(path),
+            None => {
+                let config = Config {
+                    routes: self.routes.clone(),
+                    vm_owner: self.vm_owner.clone(),
+                    vm_name: self.vm_name.clone(),
+                    wsl_interface: self.wsl_interface.clone(),
+                    networking_mode: self.networking_mode,
+                    wsl_guest_address: self.wsl_guest_address,
+                    log_level: self.log_level,
+                    log_format: self.log_format,
+                    log_dir: self.log_dir.clone(),
+                    log_max_size: self.log_max_size,
+                    log_max_files: self.log_max_files,
+                    log_max_age: self.log_max_age,
+                    poll_interval: self.poll_interval,
+                    metric: self.metric,
+                    keep_routes_on_stop: self.keep_routes_on_stop,
+                    vms: vec![],
+                };
+
+                config.validate()?;
+                Ok(config)
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RunCommandArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Run in this console instead of as a service, logging to stderr until Ctrl+C is pressed.
+    #[clap(long)]
+    pub foreground: bool,
 }
 
 #[derive(Args, Debug)]
 pub struct ChangeRoutesArgs {
-    /// Route in the format IP/MASK. This argument can be repeated. For example: -r 10.1.0.0/16 -r 10.96.0.0/12
+    /// Route in the format IP/MASK[,metric=METRIC][,via=IP]. This argument can be repeated. For example: -r 10.1.0.0/16 -r 10.96.0.0/12,metric=5 -r fd00:10:96::/112
     #[clap(
         action(clap::ArgAction::Append),
         long("route"),
@@ -41,13 +168,13 @@ pub struct ChangeRoutesArgs {
         value_parser  = validate_route,
         value_name = "ROUTE"
     )]
-    pub routes: Vec<Ipv4Network>,
+    pub routes: Vec<RouteSpec>,
 }
 
 #[derive(Subcommand)]
 #[clap(name = "Route to WSL")]
 #[clap(
-    about = "Configures rules in the IPv4 routing table to forward specific IP traffic through WSL"
+    about = "Configures rules in the IPv4 and IPv6 routing tables to forward specific IP traffic through WSL"
 )]
 pub enum Commands {
     /// Installs the service
@@ -56,25 +183,143 @@ pub enum Commands {
     /// Uninstalls the service
     Uninstall,
 
-    /// Runs the service
-    #[clap(hide = true)]
-    Run(RunArgs),
+    /// Runs the service. With --foreground it runs in this console instead, for diagnosing detection problems
+    Run(RunCommandArgs),
 
     /// Prints details about the existing installation
     Inspect,
 
+    /// Prints the state of the service, the detected VMs and whether their routes are in the routing table
+    Status,
+
     /// Adds a route to the configuration
-    AddRoute(ChangeRoutesArgs)
+    AddRoute(ChangeRoutesArgs),
+
+    /// Removes a route from the configuration
+    RemoveRoute(ChangeRoutesArgs),
+
+    /// Replaces the configured routes
+    SetRoutes(ChangeRoutesArgs),
+
+    /// Prints the routes the running service has added to the routing table
+    ManagedRoutes,
+
+    /// Has the running service detect the VMs again and check the routing table right away
+    Resync,
+
+    /// Changes the log level of the running service until it restarts or reloads its configuration
+    LogLevel(LogLevelArgs),
+
+    /// Checks .wslconfig and a distro's wsl.conf for settings that keep routes from working
+    Doctor(DoctorArgs),
 }
 
-pub fn validate_route(val: &str) -> Result<Ipv4Network, String> {
+#[derive(Args, Debug)]
+pub struct LogLevelArgs {
+    pub level: LevelFilter,
+}
+
+#[derive(Args, Debug)]
+pub struct DoctorArgs {
+    /// Path of a .wslconfig file to check instead of the ones in the user profiles.
+    #[clap(long, value_name = "PATH")]
+    pub wslconfig: Option<PathBuf>,
+
+    /// Check the /etc/wsl.conf of this running distro.
+    #[clap(long, value_name = "NAME", conflicts_with = "wsl_conf")]
+    pub distro: Option<String>,
+
+    /// Path of a wsl.conf file to check.
+    #[clap(long, value_name = "PATH")]
+    pub wsl_conf: Option<PathBuf>,
+}
+
+pub fn validate_route(val: &str) -> Result<RouteSpec, String> {
+    val.parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_accepts_a_config_file_instead_of_routes() {
+        let cli = Cli::try_parse_from(["route2wsl", "run", "--config", r"C:\ProgramData\route2wsl\config.json"]).unwrap();
+
+        let Commands::Run(args) = cli.command else {
+            panic!("Expected the run command");
+        };
+
+        assert_eq!(args.run.config, Some(PathBuf::from(r"C:\ProgramData\route2wsl\config.json")));
+        assert!(args.run.routes.is_empty());
+        assert!(!args.foreground);
+    }
+
+    #[test]
+    fn run_rejects_options_next_to_a_config_file() {
+        for option in [["-r", "10.1.0.0/16"], ["--log-level", "Debug"], ["--metric", "5"]] {
+            let mut args = vec!["route2wsl", "run", "--config", "config.json"];
+            args.extend(option);
+
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn run_requires_routes_without_a_config_file() {
+        assert!(Cli::try_parse_from(["route2wsl", "run"]).is_err());
+    }
+
+    #[test]
+    fn set_routes_takes_repeated_routes() {
+        let cli = Cli::try_parse_from(["route2wsl", "set-routes", "-r", "10.1.0.0/16,metric=5", "-r", "fd00:10:96::/112"]).unwrap();
+
+        let Commands::SetRoutes(args) = cli.command else {
+            panic!("Expected the set-routes command");
+        };
+
+        assert_eq!(args.routes, vec!["10.1.0.0/16,metric=5".parse::<RouteSpec>().unwrap(), "fd00:10:96::/112".parse().unwrap()]);
+    }
+
+    #[test]
+    fn run_routes_through_the_selected_vm() {
+        let cli = Cli::try_parse_from(["route2wsl", "run", "-r", "10.2.0.0/16", "--vm-owner", "DockerDesktopVM", "--vm-name", "docker-desktop"]).unwrap();
+
+        let Commands::Run(args) = cli.command else {
+            panic!("Expected the run command");
+        };
+
+        let vms = args.run.to_config().unwrap().vm_routes();
+        assert_eq!(vms[0].vm(), "DockerDesktopVM/docker-desktop");
+    }
 
-    if !val.contains('/') {
-        return Err(String::from("Use CIDR format like 10.0.0.0/24"));
-    }   
+    #[test]
+    fn run_can_stay_in_the_foreground() {
+        let cli = Cli::try_parse_from(["route2wsl", "run", "--foreground", "-r", "10.1.0.0/16"]).unwrap();
 
-    match val.parse::<Ipv4Network>() {
-        Ok(network) => Ok(network),
-        Err(e) => Err(format!("{}", e)),
+        let Commands::Run(args) = cli.command else {
+            panic!("Expected the run command");
+        };
+
+        assert!(args.foreground);
+    }
+
+    #[test]
+    fn doctor_checks_either_a_distro_or_a_wsl_conf_file() {
+        let cli = Cli::try_parse_from(["route2wsl", "doctor", "--distro", "Ubuntu"]).unwrap();
+
+        let Commands::Doctor(args) = cli.command else {
+            panic!("Expected the doctor command");
+        };
+
+        assert_eq!(args.distro.as_deref(), Some("Ubuntu"));
+        assert!(Cli::try_parse_from(["route2wsl", "doctor", "--distro", "Ubuntu", "--wsl-conf", "wsl.conf"]).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn output_is_accepted_after_the_command() {
+        let cli = Cli::try_parse_from(["route2wsl", "inspect", "--output", "json"]).unwrap();
+
+        assert_eq!(cli.output, OutputFormat::Json);
+    }
+}
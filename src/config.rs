@@ -0,0 +1,464 @@
+use std::{
+    env, fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    logging::{DEFAULT_LOG_MAX_AGE_DAYS, DEFAULT_LOG_MAX_FILES, DEFAULT_LOG_MAX_SIZE_MB, LogFormat, LogRotation},
+    routes::RouteSpec,
+    security,
+};
+
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 60;
+pub const DEFAULT_METRIC: u32 = 1;
+pub const WSL_OWNER: &str = "WSL";
+
+/// Upper bounds of the log rotation settings, which keep the sizes and ages from overflowing.
+const MAX_LOG_MAX_SIZE_MB: u64 = 1024 * 1024;
+const MAX_LOG_MAX_FILES: u32 = 1000;
+const MAX_LOG_MAX_AGE_DAYS: u64 = 100 * 365;
+
+/// Service configuration, stored as JSON so routes can be edited without re-registering the service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Routes sent through the VMs selected by `vm_owner` and `vm_name`, WSL by default.
+    #[serde(default)]
+    pub routes: Vec<RouteSpec>,
+
+    #[serde(default = "default_vm_owner")]
+    pub vm_owner: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm_name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wsl_interface: Option<String>,
+
+    /// How WSL is networked. Detected from the `.wslconfig` files of the users by default.
+    #[serde(default, skip_serializing_if = "NetworkingMode::is_auto")]
+    pub networking_mode: NetworkingMode,
+
+    /// Address of the WSL VM that routes are sent to in mirrored mode, where the VM shares the
+    /// addresses of the host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wsl_guest_address: Option<IpAddr>,
+
+    #[serde(default = "default_log_level")]
+    pub log_level: LevelFilter,
+
+    #[serde(default, skip_serializing_if = "LogFormat::is_text")]
+    pub log_format: LogFormat,
+
+    /// Directory of the service log. `%ProgramData%\route2wsl\logs` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_dir: Option<PathBuf>,
+
+    /// Megabytes the log file grows to before it is rotated.
+    #[serde(default = "default_log_max_size")]
+    pub log_max_size: u64,
+
+    /// Rotated log files that are kept.
+    #[serde(default = "default_log_max_files")]
+    pub log_max_files: u32,
+
+    /// Days after which the log file is rotated, whatever its size. Zero only rotates by size.
+    #[serde(default = "default_log_max_age")]
+    pub log_max_age: u64,
+
+    /// Seconds between routing table checks when no network change is notified.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+
+    #[serde(default = "default_metric")]
+    pub metric: u32,
+
+    #[serde(default)]
+    pub keep_routes_on_stop: bool,
+
+    /// Routes sent through the VMs of other owners, such as Docker Desktop.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vms: Vec<VmRoutes>,
+}
+
+/// The `networkingMode` of WSL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkingMode {
+    /// Detect the mode from `.wslconfig`
+    #[default]
+    Auto,
+    /// The VM is behind a NAT on the `vEthernet (WSL)` adapter
+    Nat,
+    /// The VM mirrors the network interfaces of the host
+    Mirrored,
+}
+
+impl NetworkingMode {
+    pub fn is_auto(&self) -> bool {
+        *self == NetworkingMode::Auto
+    }
+}
+
+impl fmt::Display for NetworkingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkingMode::Auto => write!(f, "auto"),
+            NetworkingMode::Nat => write!(f, "NAT"),
+            NetworkingMode::Mirrored => write!(f, "mirrored"),
+        }
+    }
+}
+
+/// Routes sent through every HCS compute system of an owner, or the one with a given name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmRoutes {
+    /// Owner of the compute systems, as listed by `hcsdiag list`. For example `WSL` or `DockerDesktopVM`.
+    pub owner: String,
+
+    /// Only route through the compute system with this name or id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    pub routes: Vec<RouteSpec>,
+}
+
+impl VmRoutes {
+    /// Identifies the VMs the routes are sent through, as `owner` or `owner/name`.
+    pub fn vm(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{}/{}", self.owner, name),
+            None => self.owner.clone(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let s = fs::read_to_string(path).map_err(|e| {
+            Error::config(format!("Failed to read config {}", path.display())).with_source(e)
+        })?;
+
+        let config: Config = serde_json::from_str(&s).map_err(|e| {
+            Error::config(format!("Failed to parse config {}", path.display())).with_source(e)
+        })?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            security::create_dir_all(dir).map_err(|e| {
+                Error::config(format!("Failed to create {}", dir.display())).with_source(e)
+            })?;
+
+            // The service reloads the config, so users must not be able to replace it
+            if dir == program_data_dir() {
+                security::restrict(dir).map_err(|e| {
+                    Error::config(format!("Failed to restrict access to {}", dir.display())).with_source(e)
+                })?;
+            }
+        }
+
+        let s = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::config("Failed to serialize config").with_source(e))?;
+        security::write_restricted(path, s).map_err(|e| {
+            Error::config(format!("Failed to write config {}", path.display())).with_source(e)
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.routes.is_empty() && self.vms.is_empty() {
+            return Err(Error::config("At least one route must be configured"));
+        }
+
+        if self.vm_owner.trim().is_empty() {
+            return Err(Error::config("The VM owner must not be empty"));
+        }
+
+        for vm in &self.vms {
+            if vm.owner.trim().is_empty() {
+                return Err(Error::config("Every VM must have an owner"));
+            }
+
+            if vm.routes.is_empty() {
+                return Err(Error::config(format!("At least one route must be configured for {}", vm.vm())));
+            }
+        }
+
+        if self.poll_interval == 0 {
+            return Err(Error::config("The poll interval must be at least one second"));
+        }
+
+        if self.log_max_size == 0 {
+            return Err(Error::config("The log file must be allowed to grow to at least one megabyte"));
+        }
+
+        if self.log_max_size > MAX_LOG_MAX_SIZE_MB {
+            return Err(Error::config(format!("The log file can grow to at most {} megabytes", MAX_LOG_MAX_SIZE_MB)));
+        }
+
+        if self.log_max_files > MAX_LOG_MAX_FILES {
+            return Err(Error::config(format!("At most {} rotated log files can be kept", MAX_LOG_MAX_FILES)));
+        }
+
+        if self.log_max_age > MAX_LOG_MAX_AGE_DAYS {
+            return Err(Error::config(format!("The log file can be rotated after at most {} days", MAX_LOG_MAX_AGE_DAYS)));
+        }
+
+        Ok(())
+    }
+
+    /// Adds routes, replacing the metric and next hop of destinations that are already configured.
+    pub fn add_routes(&mut self, routes: Vec<RouteSpec>) {
+        for route in routes {
+            match self.routes.iter_mut().find(|r| r.destination == route.destination) {
+                Some(existing) => *existing = route,
+                None => self.routes.push(route),
+            }
+        }
+    }
+
+    /// Removes the routes with the destinations of `routes`. Returns the ones that were not configured.
+    pub fn remove_routes(&mut self, routes: &[RouteSpec]) -> Vec<RouteSpec> {
+        let missing = routes
+            .iter()
+            .filter(|route| !self.routes.iter().any(|r| r.destination == route.destination))
+            .copied()
+            .collect();
+
+        self.routes.retain(|route| !routes.iter().any(|r| r.destination == route.destination));
+        missing
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval)
+    }
+
+    pub fn log_dir(&self) -> PathBuf {
+        self.log_dir.clone().unwrap_or_else(default_log_dir)
+    }
+
+    pub fn log_rotation(&self) -> LogRotation {
+        LogRotation {
+            max_size: self.log_max_size.saturating_mul(1024 * 1024),
+            max_files: self.log_max_files,
+            max_age: Duration::from_secs(self.log_max_age.saturating_mul(24 * 60 * 60)),
+        }
+    }
+
+    /// Whether the log directory, format or rotation differ. The service only applies them when it starts.
+    pub fn logging_differs(&self, other: &Config) -> bool {
+        self.log_dir() != other.log_dir()
+            || self.log_format != other.log_format
+            || self.log_rotation() != other.log_rotation()
+    }
+
+    /// The routes of every VM, starting with the VMs selected by `vm_owner` and `vm_name`.
+    pub fn vm_routes(&self) -> Vec<VmRoutes> {
+        let primary = (!self.routes.is_empty()).then(|| VmRoutes {
+            owner: self.vm_owner.clone(),
+            name: self.vm_name.clone(),
+            routes: self.routes.clone(),
+        });
+
+        primary.into_iter().chain(self.vms.iter().cloned()).collect()
+    }
+}
+
+/// `%ProgramData%\route2wsl\config.json`
+#[cfg(windows)]
+pub fn default_config_path() -> PathBuf {
+    program_data_dir().join("config.json")
+}
+
+/// `%ProgramData%\route2wsl\logs`
+pub fn default_log_dir() -> PathBuf {
+    program_data_dir().join("logs")
+}
+
+fn program_data_dir() -> PathBuf {
+    let program_data = env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(r"C:\ProgramData"));
+
+    program_data.join("route2wsl")
+}
+
+fn default_vm_owner() -> String {
+    String::from(WSL_OWNER)
+}
+
+fn default_log_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+fn default_log_max_size() -> u64 {
+    DEFAULT_LOG_MAX_SIZE_MB
+}
+
+fn default_log_max_files() -> u32 {
+    DEFAULT_LOG_MAX_FILES
+}
+
+fn default_log_max_age() -> u64 {
+    DEFAULT_LOG_MAX_AGE_DAYS
+}
+
+fn default_poll_interval() -> u64 {
+    DEFAULT_POLL_INTERVAL_SECS
+}
+
+fn default_metric() -> u32 {
+    DEFAULT_METRIC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_config_uses_defaults() {
+        let config: Config = serde_json::from_str(r#"{ "routes": ["10.152.183.0/24", "fd00:10:96::/112"] }"#).unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                routes: vec!["10.152.183.0/24".parse().unwrap(), "fd00:10:96::/112".parse().unwrap()],
+                vm_owner: String::from(WSL_OWNER),
+                vm_name: None,
+                wsl_interface: None,
+                networking_mode: NetworkingMode::Auto,
+                wsl_guest_address: None,
+                log_level: LevelFilter::Info,
+                log_format: LogFormat::Text,
+                log_dir: None,
+                log_max_size: DEFAULT_LOG_MAX_SIZE_MB,
+                log_max_files: DEFAULT_LOG_MAX_FILES,
+                log_max_age: DEFAULT_LOG_MAX_AGE_DAYS,
+                poll_interval: DEFAULT_POLL_INTERVAL_SECS,
+                metric: DEFAULT_METRIC,
+                keep_routes_on_stop: false,
+                vms: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn vms_are_routed_after_wsl() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "routes": ["10.152.183.0/24"],
+                "vms": [{ "owner": "DockerDesktopVM", "routes": ["10.2.0.0/16,metric=5"] }]
+            }"#,
+        )
+        .unwrap();
+
+        let vms = config.vm_routes();
+
+        assert_eq!(vms.len(), 2);
+        assert_eq!(vms[0].owner, WSL_OWNER);
+        assert_eq!(vms[0].routes, config.routes);
+        assert_eq!(vms[1], config.vms[0]);
+    }
+
+    #[test]
+    fn routes_can_be_sent_to_another_vm_owner() {
+        let config: Config = serde_json::from_str(
+            r#"{ "routes": ["10.2.0.0/16"], "vm_owner": "DockerDesktopVM", "vm_name": "docker-desktop" }"#,
+        )
+        .unwrap();
+
+        let vms = config.vm_routes();
+
+        assert_eq!(vms.len(), 1);
+        assert_eq!(vms[0].vm(), "DockerDesktopVM/docker-desktop");
+    }
+
+    #[test]
+    fn added_routes_replace_the_same_destination() {
+        let mut config: Config = serde_json::from_str(r#"{ "routes": ["10.1.0.0/16", "10.2.0.0/16"] }"#).unwrap();
+
+        config.add_routes(vec!["10.2.0.0/16,metric=5".parse().unwrap(), "10.3.0.0/16".parse().unwrap()]);
+        let missing = config.remove_routes(&["10.1.0.0/16".parse().unwrap(), "10.4.0.0/16".parse().unwrap()]);
+
+        assert_eq!(config.routes, vec!["10.2.0.0/16,metric=5".parse().unwrap(), "10.3.0.0/16".parse().unwrap()]);
+        assert_eq!(missing, vec!["10.4.0.0/16".parse().unwrap()]);
+    }
+
+    #[test]
+    fn vms_without_routes_are_rejected() {
+        let config: Config = serde_json::from_str(r#"{ "vms": [{ "owner": "DockerDesktopVM", "routes": [] }] }"#).unwrap();
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn log_settings_changes_are_told_apart_from_route_changes() {
+        let config: Config = serde_json::from_str(r#"{ "routes": ["10.1.0.0/16"] }"#).unwrap();
+        let routes: Config = serde_json::from_str(r#"{ "routes": ["10.2.0.0/16"], "log_level": "debug" }"#).unwrap();
+        let rotation: Config = serde_json::from_str(r#"{ "routes": ["10.1.0.0/16"], "log_max_files": 2 }"#).unwrap();
+
+        assert!(!config.logging_differs(&routes));
+        assert!(config.logging_differs(&rotation));
+    }
+
+    #[test]
+    fn config_round_trips_through_a_file() {
+        let path = env::temp_dir()
+            .join(format!("route2wsl-config-{}", std::process::id()))
+            .join("config.json");
+        let config = Config {
+            routes: vec!["10.1.0.0/16".parse().unwrap(), "10.2.0.0/16,metric=5,via=10.2.0.3".parse().unwrap()],
+            vm_owner: String::from(WSL_OWNER),
+            vm_name: None,
+            wsl_interface: Some(String::from("vEthernet (WSL)")),
+            networking_mode: NetworkingMode::Mirrored,
+            wsl_guest_address: Some("192.168.1.20".parse().unwrap()),
+            log_level: LevelFilter::Debug,
+            log_format: LogFormat::Json,
+            log_dir: Some(PathBuf::from(r"D:\Logs\route2wsl")),
+            log_max_size: 50,
+            log_max_files: 2,
+            log_max_age: 0,
+            poll_interval: 30,
+            metric: 5,
+            keep_routes_on_stop: true,
+            vms: vec![VmRoutes {
+                owner: String::from("DockerDesktopVM"),
+                name: Some(String::from("docker-desktop")),
+                routes: vec!["10.3.0.0/16".parse().unwrap()],
+            }],
+        };
+
+        config.save(&path).unwrap();
+        assert_eq!(Config::load(&path).unwrap(), config);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn log_rotation_settings_are_bounded() {
+        let huge: Config = serde_json::from_str(r#"{ "routes": ["10.1.0.0/16"], "log_max_size": 18446744073709551615 }"#).unwrap();
+        let old: Config = serde_json::from_str(r#"{ "routes": ["10.1.0.0/16"], "log_max_age": 18446744073709551615 }"#).unwrap();
+
+        assert!(huge.validate().is_err());
+        assert!(old.validate().is_err());
+        assert_eq!(huge.log_rotation().max_size, u64::MAX);
+    }
+
+    #[test]
+    fn config_without_routes_is_rejected() {
+        let config: Config = serde_json::from_str(r#"{ "routes": [] }"#).unwrap();
+
+        assert!(config.validate().is_err());
+    }
+}
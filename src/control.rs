@@ -0,0 +1,584 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, PoisonError, mpsc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{LevelFilter, debug, error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{config::Config, error::Error, routes::RouteSpec, wsl_monitor::MonitorEvent};
+
+#[cfg(windows)]
+pub use pipe::PipeListener;
+
+/// Name of the pipe the service answers control requests on.
+#[cfg(windows)]
+pub const PIPE_NAME: &str = r"\\.\pipe\route2wsl";
+
+/// Wait before accepting connections again after the first failure, doubled after each failure
+/// up to `MAX_ACCEPT_BACKOFF`.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a request waits for the monitor to answer it.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request to the running service, sent as one line of JSON such as
+/// `{"op":"set_log_level","level":"DEBUG"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// The gateways, route status and managed routes of the monitor.
+    Status,
+    /// The routes the service has added to the routing table.
+    ManagedRoutes,
+    /// Adds routes to the configuration file and applies them.
+    AddRoutes { routes: Vec<RouteSpec> },
+    /// Removes routes from the configuration file and applies the change.
+    RemoveRoutes { routes: Vec<RouteSpec> },
+    /// Replaces the routes of the configuration file and applies them.
+    SetRoutes { routes: Vec<RouteSpec> },
+    /// Detects the VMs again and reconciles the routing table right away.
+    Resync,
+    /// Changes the log level until the configuration is reloaded or the service restarts.
+    SetLogLevel { level: LevelFilter },
+}
+
+/// The requests answered by the monitor. Route changes are made to the configuration file by the
+/// control handler instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonitorRequest {
+    Status,
+    ManagedRoutes,
+    Resync,
+    SetLogLevel { level: LevelFilter },
+}
+
+/// The answer to a request, sent as one line of JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub result: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    pub fn ok(result: impl Serialize) -> Self {
+        match serde_json::to_value(result) {
+            Ok(result) => Response {
+                ok: true,
+                result,
+                error: None,
+            },
+            Err(e) => Response::error(&Error::parse("Failed to serialize the response", e)),
+        }
+    }
+
+    pub fn error(e: &Error) -> Self {
+        Response {
+            ok: false,
+            result: Value::Null,
+            error: Some(e.report()),
+        }
+    }
+
+    /// The result, or the error the service answered with.
+    pub fn into_result(self) -> Result<Value, Error> {
+        if self.ok {
+            Ok(self.result)
+        } else {
+            Err(Error::refused(self.error.unwrap_or_default()))
+        }
+    }
+}
+
+impl<T: Serialize> From<Result<T, Error>> for Response {
+    fn from(result: Result<T, Error>) -> Self {
+        match result {
+            Ok(result) => Response::ok(result),
+            Err(e) => Response::error(&e),
+        }
+    }
+}
+
+/// Answers control requests on behalf of the service. Route changes are written to the
+/// configuration file and reloaded, everything else is answered by the monitor.
+pub struct ControlHandler {
+    events: mpsc::Sender<MonitorEvent>,
+    config_path: Option<PathBuf>,
+    keep_routes_on_stop: Arc<AtomicBool>,
+    /// Held while the configuration file is read and written, as requests are answered on their
+    /// own threads and reloads come from the Service Control Manager.
+    config_lock: Mutex<()>,
+}
+
+impl ControlHandler {
+    pub fn new(
+        events: mpsc::Sender<MonitorEvent>,
+        config_path: Option<PathBuf>,
+        keep_routes_on_stop: Arc<AtomicBool>,
+    ) -> Self {
+        ControlHandler {
+            events,
+            config_path,
+            keep_routes_on_stop,
+            config_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::AddRoutes { routes } => self
+                .change_config(|config| {
+                    config.add_routes(routes);
+                    config.validate()
+                })
+                .into(),
+            Request::RemoveRoutes { routes } => self
+                .change_config(|config| {
+                    config.remove_routes(&routes);
+                    config.validate()
+                })
+                .into(),
+            Request::SetRoutes { routes } => self
+                .change_config(|config| {
+                    config.routes.clear();
+                    config.add_routes(routes);
+                    config.validate()
+                })
+                .into(),
+            Request::Status => self.forward(MonitorRequest::Status),
+            Request::ManagedRoutes => self.forward(MonitorRequest::ManagedRoutes),
+            Request::Resync => self.forward(MonitorRequest::Resync),
+            Request::SetLogLevel { level } => self.forward(MonitorRequest::SetLogLevel { level }),
+        }
+    }
+
+    /// Loads the configuration file and has the monitor apply it.
+    #[cfg(windows)]
+    pub fn reload(&self) -> Result<Config, Error> {
+        let _lock = self.config_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let config = Config::load(self.config_path()?)?;
+        self.apply(config.clone())?;
+        Ok(config)
+    }
+
+    /// Changes the configuration file and has the monitor apply it. Returns the configured routes.
+    fn change_config(
+        &self,
+        change: impl FnOnce(&mut Config) -> Result<(), Error>,
+    ) -> Result<Vec<RouteSpec>, Error> {
+        let path = self.config_path()?;
+        let _lock = self.config_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut config = Config::load(path)?;
+
+        change(&mut config)?;
+        config.save(path)?;
+
+        let routes = config.routes.clone();
+        self.apply(config)?;
+        Ok(routes)
+    }
+
+    fn apply(&self, config: Config) -> Result<(), Error> {
+        self.keep_routes_on_stop
+            .store(config.keep_routes_on_stop, Ordering::Relaxed);
+
+        self.events
+            .send(MonitorEvent::Reload(Box::new(config)))
+            .map_err(|e| Error::control("The monitor has stopped", e.to_string()))
+    }
+
+    fn config_path(&self) -> Result<&Path, Error> {
+        self.config_path.as_deref().ok_or_else(|| {
+            Error::config("Routes can only be changed when the service runs with a configuration file")
+        })
+    }
+
+    fn forward(&self, request: MonitorRequest) -> Response {
+        let (reply, answer) = mpsc::channel();
+
+        if let Err(e) = self.events.send(MonitorEvent::Control(request, reply)) {
+            return Response::error(&Error::control("The monitor has stopped", e.to_string()));
+        }
+
+        answer
+            .recv_timeout(REPLY_TIMEOUT)
+            .unwrap_or_else(|e| Response::error(&Error::control("The monitor did not answer in time", e)))
+    }
+}
+
+/// Answers the requests of each client on a thread of its own, so a client that sends nothing
+/// doesn't keep the others waiting.
+pub fn serve<S: Read + Write + Send>(
+    clients: impl IntoIterator<Item = io::Result<S>>,
+    handle: impl Fn(Request) -> Response + Sync,
+) {
+    let handle = &handle;
+    let mut backoff = Duration::ZERO;
+
+    thread::scope(|scope| {
+        for client in clients {
+            match client {
+                Ok(stream) => {
+                    backoff = Duration::ZERO;
+
+                    scope.spawn(move || {
+                        if let Err(e) = serve_connection(stream, handle) {
+                            debug!("Control connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    // Retrying at once would keep failing in a busy loop
+                    backoff = (backoff * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+                    error!("Failed to accept a control connection, retrying in {:?}: {}", backoff, e);
+                    thread::sleep(backoff);
+                }
+            }
+        }
+    });
+}
+
+/// Answers requests, one JSON line each, until the client disconnects.
+fn serve_connection<S: Read + Write>(stream: S, handle: impl Fn(Request) -> Response) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                debug!("Control request {:?}", request);
+                handle(request)
+            }
+            Err(e) => Response::error(&Error::parse("Invalid control request", e)),
+        };
+
+        let mut reply = serde_json::to_string(&response)?;
+        reply.push('\n');
+
+        let stream = reader.get_mut();
+        stream.write_all(reply.as_bytes())?;
+        stream.flush()?;
+    }
+}
+
+/// Sends a request to the service over a connection and returns the result.
+pub fn send<S: Read + Write>(stream: S, request: &Request) -> Result<Value, Error> {
+    let mut reader = BufReader::new(stream);
+
+    let mut line = serde_json::to_string(request)
+        .map_err(|e| Error::parse("Failed to serialize the control request", e))?;
+    line.push('\n');
+
+    let stream = reader.get_mut();
+    stream
+        .write_all(line.as_bytes())
+        .and_then(|_| stream.flush())
+        .map_err(|e| Error::control("Failed to send the control request", e))?;
+
+    line.clear();
+    reader
+        .read_line(&mut line)
+        .map_err(|e| Error::control("Failed to read the control response", e))?;
+
+    serde_json::from_str::<Response>(&line)
+        .map_err(|e| Error::parse("Invalid control response", e))?
+        .into_result()
+}
+
+/// Sends a request to the running service.
+#[cfg(windows)]
+pub fn request(request: &Request) -> Result<Value, Error> {
+    let pipe = pipe::open(PIPE_NAME).map_err(|e| Error::control("Failed to connect to the service, is it running?", e))?;
+
+    pipe::check_server(&pipe).map_err(|e| Error::control(format!("Refusing to send requests to {}", PIPE_NAME), e))?;
+
+    send(pipe, request)
+}
+
+#[cfg(windows)]
+mod pipe {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{self, Read, Write},
+        os::windows::{fs::OpenOptionsExt, io::AsRawHandle},
+    };
+
+    use windows::{
+        Win32::{
+            Foundation::{
+                CloseHandle, ERROR_BROKEN_PIPE, ERROR_PIPE_CONNECTED, HANDLE, HLOCAL, INVALID_HANDLE_VALUE, LocalFree,
+            },
+            Security::{
+                Authorization::{GetSecurityInfo, SE_KERNEL_OBJECT},
+                IsWellKnownSid, OWNER_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR, PSID, WinBuiltinAdministratorsSid,
+                WinLocalSystemSid,
+            },
+            Storage::FileSystem::{
+                FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAGS_AND_ATTRIBUTES, FlushFileBuffers, PIPE_ACCESS_DUPLEX,
+                ReadFile, SECURITY_IDENTIFICATION, SECURITY_SQOS_PRESENT, WriteFile,
+            },
+            System::Pipes::{
+                ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, PIPE_READMODE_BYTE,
+                PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+            },
+        },
+        core::HSTRING,
+    };
+
+    use crate::security::SecurityDescriptor;
+
+    const BUFFER_SIZE: u32 = 4096;
+
+    /// Only SYSTEM and Administrators can open the pipe.
+    const PIPE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+    /// Waits for clients of a named pipe, creating an instance of the pipe for each of them.
+    /// An instance that no client is connected to is kept open at all times, so that no other
+    /// process can take the name of the pipe while the service runs.
+    pub struct PipeListener {
+        name: HSTRING,
+        descriptor: SecurityDescriptor,
+        next: HANDLE,
+    }
+
+    impl PipeListener {
+        /// Creates the first instance of the pipe. Fails if another process already created a
+        /// pipe with the name.
+        pub fn new(name: &str) -> io::Result<Self> {
+            let name = HSTRING::from(name);
+            let descriptor = SecurityDescriptor::from_sddl(PIPE_SDDL)?;
+            let next = create_instance(&name, &descriptor, FILE_FLAG_FIRST_PIPE_INSTANCE)?;
+
+            Ok(PipeListener {
+                name,
+                descriptor,
+                next,
+            })
+        }
+
+        fn accept(&mut self) -> io::Result<PipeStream> {
+            let next = create_instance(&self.name, &self.descriptor, FILE_FLAGS_AND_ATTRIBUTES(0))?;
+            let stream = PipeStream(std::mem::replace(&mut self.next, next));
+
+            match unsafe { ConnectNamedPipe(stream.0, None) } {
+                Ok(()) => Ok(stream),
+                // The client connected before the pipe started waiting
+                Err(e) if e.code() == ERROR_PIPE_CONNECTED.to_hresult() => Ok(stream),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    impl Drop for PipeListener {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = CloseHandle(self.next);
+            }
+        }
+    }
+
+    fn create_instance(
+        name: &HSTRING,
+        descriptor: &SecurityDescriptor,
+        flags: FILE_FLAGS_AND_ATTRIBUTES,
+    ) -> io::Result<HANDLE> {
+        let attributes = descriptor.attributes();
+
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name,
+                PIPE_ACCESS_DUPLEX | flags,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                Some(&attributes),
+            )
+        };
+
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(handle)
+    }
+
+    /// Opens the client end of a pipe. The server may only identify the client, so a user who
+    /// created the pipe first can't impersonate the administrator running the command.
+    pub fn open(name: &str) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .security_qos_flags(SECURITY_SQOS_PRESENT.0 | SECURITY_IDENTIFICATION.0)
+            .open(name)
+    }
+
+    /// Makes sure the pipe belongs to SYSTEM or Administrators, as the pipe of the service does,
+    /// rather than to a user who created it first.
+    pub fn check_server(pipe: &File) -> io::Result<()> {
+        let mut owner = PSID::default();
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+
+        unsafe {
+            GetSecurityInfo(
+                HANDLE(pipe.as_raw_handle()),
+                SE_KERNEL_OBJECT,
+                OWNER_SECURITY_INFORMATION,
+                Some(&mut owner),
+                None,
+                None,
+                None,
+                Some(&mut descriptor),
+            )
+            .ok()?;
+        }
+
+        let trusted = unsafe {
+            IsWellKnownSid(owner, WinLocalSystemSid).as_bool()
+                || IsWellKnownSid(owner, WinBuiltinAdministratorsSid).as_bool()
+        };
+
+        unsafe {
+            LocalFree(Some(HLOCAL(descriptor.0)));
+        }
+
+        if trusted {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "the pipe was not created by the service"))
+        }
+    }
+
+    impl Iterator for PipeListener {
+        type Item = io::Result<PipeStream>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            Some(self.accept())
+        }
+    }
+
+    /// A connected instance of a named pipe, disconnected and closed when dropped.
+    pub struct PipeStream(HANDLE);
+
+    // The handle is only used by the thread that owns the stream
+    unsafe impl Send for PipeStream {}
+
+    impl Read for PipeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut read = 0;
+
+            match unsafe { ReadFile(self.0, Some(buf), Some(&mut read), None) } {
+                Ok(()) => Ok(read as usize),
+                Err(e) if e.code() == ERROR_BROKEN_PIPE.to_hresult() => Ok(0),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    impl Write for PipeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut written = 0;
+
+            unsafe { WriteFile(self.0, Some(buf), Some(&mut written), None) }?;
+            Ok(written as usize)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            unsafe { FlushFileBuffers(self.0) }?;
+            Ok(())
+        }
+    }
+
+    impl Drop for PipeStream {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = DisconnectNamedPipe(self.0);
+                let _ = CloseHandle(self.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_tagged_by_op() {
+        let request: Request = serde_json::from_str(r#"{"op":"add_routes","routes":["10.96.0.0/12,metric=5"]}"#).unwrap();
+
+        assert_eq!(
+            request,
+            Request::AddRoutes {
+                routes: vec!["10.96.0.0/12,metric=5".parse().unwrap()]
+            }
+        );
+        assert_eq!(serde_json::to_string(&Request::Resync).unwrap(), r#"{"op":"resync"}"#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn requests_are_answered_over_a_unix_socket() {
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let path = std::env::temp_dir().join(format!("route2wsl-control-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        std::thread::spawn(move || {
+            serve(listener.incoming(), |request| match request {
+                Request::SetLogLevel { level } => Response::ok(level),
+                _ => Response::error(&Error::not_found("Unsupported")),
+            })
+        });
+
+        // Never sends a request, which must not keep the next client waiting
+        let _idle = UnixStream::connect(&path).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let level = send(&mut stream, &Request::SetLogLevel { level: LevelFilter::Debug }).unwrap();
+        let error = send(&mut stream, &Request::Resync).unwrap_err();
+
+        assert_eq!(level, Value::from("DEBUG"));
+        assert_eq!(error.to_string(), "Unsupported");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn route_changes_are_saved_and_reloaded() {
+        let path = std::env::temp_dir()
+            .join(format!("route2wsl-control-{}", std::process::id()))
+            .join("config.json");
+        let config: Config = serde_json::from_str(r#"{ "routes": ["10.1.0.0/16"] }"#).unwrap();
+        config.save(&path).unwrap();
+
+        let (events, received) = mpsc::channel();
+        let handler = ControlHandler::new(events, Some(path.clone()), Arc::new(AtomicBool::new(false)));
+
+        let response = handler.handle(Request::AddRoutes {
+            routes: vec!["10.2.0.0/16".parse().unwrap()],
+        });
+
+        assert_eq!(response, Response::ok(["10.1.0.0/16", "10.2.0.0/16"]));
+        assert_eq!(Config::load(&path).unwrap().routes.len(), 2);
+        assert!(matches!(received.try_recv(), Ok(MonitorEvent::Reload(c)) if c.routes.len() == 2));
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    fmt::{Display, Formatter},
+    path::PathBuf,
+};
+
+#[cfg(windows)]
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::{
+    config::NetworkingMode,
+    output::Report,
+    wsl_monitor::DetectionOptions,
+    wslconfig::{self, WslConf, WslConfig, WslNetworkingMode},
+};
+#[cfg(windows)]
+use crate::{cli::DoctorArgs, config::Config, error::Error};
+
+/// Problems with the WSL settings that keep routes from working, printed by `doctor`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Diagnosis {
+    /// The `.wslconfig` and `wsl.conf` files that were read.
+    pub files: Vec<PathBuf>,
+    pub findings: Vec<Finding>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The file or configuration the finding is about.
+    pub source: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    /// Routes can't work until it is fixed.
+    Error,
+}
+
+/// Reads the WSL settings and checks them against the configuration of the service.
+#[cfg(windows)]
+pub fn run(args: &DoctorArgs, config: Option<&Config>) -> Result<Diagnosis, Error> {
+    let wslconfigs = match &args.wslconfig {
+        Some(path) => vec![(
+            path.clone(),
+            WslConfig::load(path)
+                .map_err(|e| Error::io(format!("Failed to read {}", path.display()), e))?,
+        )],
+        None => wslconfig::user_configs(),
+    };
+
+    let wsl_conf_path = match (&args.wsl_conf, &args.distro) {
+        (Some(path), _) => Some(path.clone()),
+        (None, Some(distro)) => Some(WslConf::distro_path(distro)),
+        (None, None) => None,
+    };
+
+    let wsl_conf = match wsl_conf_path {
+        Some(path) => {
+            let conf = WslConf::load(&path)
+                .map_err(|e| Error::io(format!("Failed to read {}", path.display()), e))?;
+            Some((path, conf))
+        }
+        None => None,
+    };
+
+    let options = config.map(DetectionOptions::from).unwrap_or_default();
+
+    Ok(diagnose(&wslconfigs, wsl_conf.as_ref(), &options))
+}
+
+/// Checks the `.wslconfig` files of the users and optionally the `wsl.conf` of a distro.
+pub fn diagnose(
+    wslconfigs: &[(PathBuf, WslConfig)],
+    wsl_conf: Option<&(PathBuf, WslConf)>,
+    options: &DetectionOptions,
+) -> Diagnosis {
+    let mut diagnosis = Diagnosis {
+        files: wslconfigs
+            .iter()
+            .map(|(path, _)| path.clone())
+            .chain(wsl_conf.map(|(path, _)| path.clone()))
+            .collect(),
+        findings: vec![],
+    };
+
+    if wslconfigs.is_empty() {
+        diagnosis.add(
+            Severity::Info,
+            ".wslconfig",
+            "No .wslconfig file was found, so WSL uses NAT networking",
+        );
+    }
+
+    for (path, config) in wslconfigs {
+        diagnosis.check_wslconfig(&path.display().to_string(), config);
+    }
+
+    let detected = wslconfig::networking_mode_of(wslconfigs);
+    let mode = match options.networking_mode {
+        NetworkingMode::Auto => detected,
+        configured => {
+            if configured != detected {
+                diagnosis.add(
+                    Severity::Warning,
+                    "config",
+                    format!(
+                        "The networking mode is set to {} but .wslconfig uses {}",
+                        configured, detected
+                    ),
+                );
+            }
+            configured
+        }
+    };
+
+    match (mode, options.wsl_guest_address) {
+        (NetworkingMode::Mirrored, None) => diagnosis.add(
+            Severity::Error,
+            "config",
+            "Mirrored networking needs an address of the WSL VM to route to; set it with --wsl-guest-address",
+        ),
+        (NetworkingMode::Mirrored, Some(address)) => {
+            if let Some((path, conf)) = wsl_conf
+                && !conf.boot_command.as_deref().is_some_and(|c| c.contains(&address.to_string()))
+            {
+                diagnosis.add(
+                    Severity::Info,
+                    path.display().to_string(),
+                    format!("The [boot] command does not add {}; make sure the distro adds it to its interface", address),
+                );
+            }
+        }
+        (_, Some(address)) => diagnosis.add(
+            Severity::Info,
+            "config",
+            format!("The guest address {} is only used in mirrored mode", address),
+        ),
+        (_, None) => {}
+    }
+
+    if let Some((path, conf)) = wsl_conf
+        && conf.generate_resolv_conf == Some(false)
+        && wslconfigs
+            .iter()
+            .any(|(_, config)| config.dns_tunneling == Some(true))
+    {
+        diagnosis.add(
+            Severity::Warning,
+            path.display().to_string(),
+            "generateResolvConf=false keeps the distro from using the DNS tunneling turned on in .wslconfig",
+        );
+    }
+
+    diagnosis
+}
+
+impl Diagnosis {
+    fn add(&mut self, severity: Severity, source: impl Into<String>, message: impl Into<String>) {
+        self.findings.push(Finding {
+            severity,
+            source: source.into(),
+            message: message.into(),
+        });
+    }
+
+    fn check_wslconfig(&mut self, source: &str, config: &WslConfig) {
+        if !config.misplaced.is_empty() {
+            self.add(
+                Severity::Warning,
+                source,
+                format!(
+                    "WSL ignores {} in [experimental]; move them to [wsl2]",
+                    config.misplaced.join(", ")
+                ),
+            );
+        }
+
+        match &config.networking_mode {
+            Some(WslNetworkingMode::Mirrored) if config.firewall != Some(false) => self.add(
+                Severity::Warning,
+                source,
+                "The Hyper-V firewall filters traffic routed to the WSL VM in mirrored mode; allow it with a Hyper-V firewall rule or set firewall=false",
+            ),
+            Some(mode) if mode.supported().is_none() => self.add(
+                Severity::Error,
+                source,
+                format!("networkingMode={} is not supported; routes can only be sent to NAT or mirrored networking", mode),
+            ),
+            _ => {}
+        }
+
+        if let Some(timeout) = config.vm_idle_timeout
+            && timeout >= 0
+        {
+            self.add(
+                Severity::Info,
+                source,
+                format!(
+                    "The WSL VM stops {} seconds after its last process exits, and its routes don't work until it starts again",
+                    timeout / 1000
+                ),
+            );
+        }
+    }
+
+    /// Writes the findings to the log of the service.
+    #[cfg(windows)]
+    pub fn log(&self) {
+        for finding in &self.findings {
+            match finding.severity {
+                Severity::Info => info!("{}", finding),
+                Severity::Warning => warn!("{}", finding),
+                Severity::Error => error!("{}", finding),
+            }
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.source, self.message)
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl Report for Diagnosis {
+    fn text(&self) -> String {
+        let mut lines: Vec<String> = self
+            .files
+            .iter()
+            .map(|path| format!("Read {}", path.display()))
+            .collect();
+
+        if self.findings.is_empty() {
+            lines.push(String::from("No problems found"));
+        }
+
+        for finding in &self.findings {
+            lines.push(format!("{}: {}", finding.severity, finding));
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    const MIRRORED: &str = include_str!("../tests/fixtures/wsl/wslconfig-mirrored");
+    const EXPERIMENTAL: &str = include_str!("../tests/fixtures/wsl/wslconfig-experimental");
+    const BRIDGED: &str = include_str!("../tests/fixtures/wsl/wslconfig-bridged");
+    const WSL_CONF: &str = include_str!("../tests/fixtures/wsl/wsl.conf");
+
+    fn wslconfig(s: &str) -> Vec<(PathBuf, WslConfig)> {
+        vec![(PathBuf::from(".wslconfig"), WslConfig::parse(s))]
+    }
+
+    fn severities(diagnosis: &Diagnosis) -> Vec<(Severity, &str)> {
+        diagnosis
+            .findings
+            .iter()
+            .map(|f| (f.severity, f.source.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn mirrored_networking_without_a_guest_address_is_an_error() {
+        let diagnosis = diagnose(&wslconfig(MIRRORED), None, &DetectionOptions::default());
+
+        assert_eq!(
+            severities(&diagnosis),
+            vec![
+                (Severity::Warning, ".wslconfig"),
+                (Severity::Info, ".wslconfig"),
+                (Severity::Error, "config"),
+            ]
+        );
+    }
+
+    #[test]
+    fn wsl_conf_is_checked_against_the_guest_address_and_dns_tunneling() {
+        let wsl_conf = (PathBuf::from("wsl.conf"), WslConf::parse(WSL_CONF));
+        let options = DetectionOptions {
+            wsl_guest_address: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))),
+            ..DetectionOptions::default()
+        };
+
+        let diagnosis = diagnose(&wslconfig(MIRRORED), Some(&wsl_conf), &options);
+
+        assert_eq!(
+            severities(&diagnosis),
+            vec![
+                (Severity::Warning, ".wslconfig"),
+                (Severity::Info, ".wslconfig"),
+                (Severity::Warning, "wsl.conf"),
+            ]
+        );
+        assert_eq!(
+            diagnosis.files,
+            vec![PathBuf::from(".wslconfig"), PathBuf::from("wsl.conf")]
+        );
+    }
+
+    #[test]
+    fn unsupported_and_misplaced_settings_are_reported() {
+        let bridged = diagnose(&wslconfig(BRIDGED), None, &DetectionOptions::default());
+        assert_eq!(severities(&bridged), vec![(Severity::Error, ".wslconfig")]);
+        assert!(bridged.text().starts_with("Read .wslconfig\nerror: .wslconfig: "));
+
+        let options = DetectionOptions {
+            networking_mode: NetworkingMode::Mirrored,
+            wsl_guest_address: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))),
+            ..DetectionOptions::default()
+        };
+        let experimental = diagnose(&wslconfig(EXPERIMENTAL), None, &options);
+        assert_eq!(
+            severities(&experimental),
+            vec![
+                (Severity::Warning, ".wslconfig"),
+                (Severity::Warning, "config")
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt::{Display, Formatter};
+
+/// The error that caused an error, kept so that the whole chain can be reported.
+pub type Source = Box<dyn std::error::Error + Send + Sync>;
+
+/// Errors of route2wsl, by the subsystem that failed. Each carries a message describing what was
+/// being done and, where there is one, the error that caused it.
+#[derive(Debug)]
+pub enum Error {
+    /// A Host Compute Service call failed.
+    Hcs(String, Option<Source>),
+    /// A Host Compute Network call failed.
+    Hcn(String, Option<Source>),
+    /// An IP Helper call failed with a Win32 error code.
+    IpHelper { message: String, code: u32 },
+    /// A Service Control Manager call failed.
+    Scm(String, Option<Source>),
+    /// The configuration could not be read, written or is invalid.
+    Config(String, Option<Source>),
+    /// A document returned by Windows or read from disk could not be parsed.
+    Parse(String, Option<Source>),
+    /// A VM, network or interface could not be found.
+    NotFound(String),
+    /// A file or the network adapter list could not be accessed.
+    Io(String, Option<Source>),
+    /// The control channel of the running service could not be used, or the service rejected the request.
+    Control(String, Option<Source>),
+}
+
+impl Error {
+    pub fn hcs(message: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::Hcs(message.into(), Some(source.into()))
+    }
+
+    pub fn hcn(message: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::Hcn(message.into(), Some(source.into()))
+    }
+
+    pub fn scm(message: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::Scm(message.into(), Some(source.into()))
+    }
+
+    pub fn config(message: impl Into<String>) -> Self {
+        Error::Config(message.into(), None)
+    }
+
+    pub fn parse(message: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::Parse(message.into(), Some(source.into()))
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Error::NotFound(message.into())
+    }
+
+    pub fn io(message: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::Io(message.into(), Some(source.into()))
+    }
+
+    pub fn control(message: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::Control(message.into(), Some(source.into()))
+    }
+
+    /// The service answered a control request with an error, which is kept as the service worded it.
+    pub fn refused(message: impl Into<String>) -> Self {
+        Error::Control(message.into(), None)
+    }
+
+    /// Keeps the error that caused this one, for errors whose constructor takes no source.
+    pub fn with_source(mut self, source: impl Into<Source>) -> Self {
+        match &mut self {
+            Error::Hcs(_, cause)
+            | Error::Hcn(_, cause)
+            | Error::Scm(_, cause)
+            | Error::Config(_, cause)
+            | Error::Parse(_, cause)
+            | Error::Io(_, cause)
+            | Error::Control(_, cause) => *cause = Some(source.into()),
+            Error::NotFound(_) | Error::IpHelper { .. } => {}
+        }
+
+        self
+    }
+
+    /// Prefixes the message with what was being done, keeping the kind of error.
+    pub fn context(mut self, context: impl Display) -> Self {
+        match &mut self {
+            Error::Hcs(message, _)
+            | Error::Hcn(message, _)
+            | Error::Scm(message, _)
+            | Error::Config(message, _)
+            | Error::Parse(message, _)
+            | Error::NotFound(message)
+            | Error::Io(message, _)
+            | Error::Control(message, _)
+            | Error::IpHelper { message, .. } => *message = format!("{}: {}", context, message),
+        }
+
+        self
+    }
+
+    /// The message followed by the messages of the errors that caused it.
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+
+        while let Some(e) = source {
+            report.push_str(&format!(": {}", e));
+            source = e.source();
+        }
+
+        report
+    }
+
+    /// Name of the kind of error, as used in JSON output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Hcs(..) => "hcs",
+            Error::Hcn(..) => "hcn",
+            Error::IpHelper { .. } => "ip_helper",
+            Error::Scm(..) => "scm",
+            Error::Config(..) => "config",
+            Error::Parse(..) => "parse",
+            Error::NotFound(..) => "not_found",
+            Error::Io(..) => "io",
+            Error::Control(..) => "control",
+        }
+    }
+
+    /// Process exit code of the CLI. 1 is left for unexpected failures and 2 for invalid arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(..) => 3,
+            Error::Parse(..) => 4,
+            Error::Scm(..) => 5,
+            Error::Hcs(..) => 6,
+            Error::Hcn(..) => 7,
+            Error::IpHelper { .. } => 8,
+            Error::NotFound(..) => 9,
+            Error::Io(..) => 10,
+            Error::Control(..) => 11,
+        }
+    }
+
+    /// The Win32 error code of a failed IP Helper call, or the first OS error or HRESULT among the
+    /// errors that caused the error.
+    pub fn code(&self) -> Option<u32> {
+        if let Error::IpHelper { code, .. } = self {
+            return Some(*code);
+        }
+
+        let mut source = std::error::Error::source(self);
+
+        while let Some(e) = source {
+            if let Some(code) = source_code(e) {
+                return Some(code);
+            }
+
+            source = e.source();
+        }
+
+        None
+    }
+
+    /// Service specific exit code reported to the Service Control Manager when the service fails.
+    pub fn service_exit_code(&self) -> u32 {
+        self.exit_code() as u32
+    }
+}
+
+fn source_code(e: &(dyn std::error::Error + 'static)) -> Option<u32> {
+    if let Some(e) = e.downcast_ref::<Error>() {
+        return e.code();
+    }
+
+    if let Some(code) = e.downcast_ref::<std::io::Error>().and_then(std::io::Error::raw_os_error) {
+        return Some(code as u32);
+    }
+
+    #[cfg(windows)]
+    if let Some(e) = e.downcast_ref::<windows::core::Error>() {
+        return Some(e.code().0 as u32);
+    }
+
+    None
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Hcs(message, _)
+            | Error::Hcn(message, _)
+            | Error::Scm(message, _)
+            | Error::Config(message, _)
+            | Error::Parse(message, _)
+            | Error::NotFound(message)
+            | Error::Io(message, _)
+            | Error::Control(message, _) => write!(f, "{}", message),
+            Error::IpHelper { message, code } => write!(f, "{} (error {})", message, code),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Hcs(_, source)
+            | Error::Hcn(_, source)
+            | Error::Scm(_, source)
+            | Error::Config(_, source)
+            | Error::Parse(_, source)
+            | Error::Io(_, source)
+            | Error::Control(_, source) => source.as_deref().map(|e| e as _),
+            Error::IpHelper { .. } | Error::NotFound(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_includes_the_source_chain() {
+        let json = serde_json::from_str::<u32>("x").unwrap_err();
+        let inner = Error::parse("Failed to parse managed-routes.json", json);
+        let outer = Error::Config(String::from("Failed to load the state"), Some(Box::new(inner)));
+
+        assert!(outer.report().starts_with("Failed to load the state: Failed to parse managed-routes.json: expected value"));
+        assert_eq!(outer.exit_code(), 3);
+        assert_eq!(outer.code(), None);
+    }
+
+    #[test]
+    fn exit_codes_tell_what_failed() {
+        let io = || std::io::Error::from(std::io::ErrorKind::Other);
+        let errors = [
+            Error::config("Invalid route"),
+            Error::scm("Failed to open the service", io()),
+            Error::hcs("Failed to enumerate compute systems", io()),
+            Error::hcn("Failed to enumerate endpoints", io()),
+            Error::not_found("No WSL VM found"),
+        ];
+
+        let codes: Vec<u32> = errors.iter().map(Error::service_exit_code).collect();
+
+        assert_eq!(codes, vec![3, 5, 6, 7, 9]);
+    }
+
+    #[test]
+    fn code_is_taken_from_ip_helper_and_os_errors() {
+        let ip_helper = Error::IpHelper {
+            message: String::from("Failed to add route"),
+            code: 5010,
+        };
+        let io = Error::io("Failed to get network adapters", std::io::Error::from_raw_os_error(5));
+
+        assert_eq!(ip_helper.code(), Some(5010));
+        assert_eq!(io.code(), Some(5));
+    }
+
+    #[test]
+    fn code_is_found_further_down_the_source_chain() {
+        let inner = Error::io("Failed to open the state file", std::io::Error::from_raw_os_error(32));
+        let outer = Error::config("Failed to load the state").with_source(inner);
+
+        assert_eq!(outer.code(), Some(32));
+        assert_eq!(Error::config("Invalid").code(), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn code_is_taken_from_hresults() {
+        use windows::Win32::Foundation::E_ACCESSDENIED;
+
+        let hcn = Error::hcn("Failed to enumerate endpoints", windows::core::Error::from(E_ACCESSDENIED));
+
+        assert_eq!(hcn.code(), Some(0x80070005));
+    }
+}
@@ -0,0 +1,216 @@
+use std::ffi::c_void;
+
+use windows::{
+    core::{GUID, HSTRING, PWSTR},
+    Win32::{
+        Foundation::{HLOCAL, LocalFree},
+        System::{
+            Com::CoTaskMemFree,
+            HostComputeNetwork::{
+                HcnCloseEndpoint, HcnCloseNetwork, HcnOpenEndpoint, HcnOpenNetwork,
+                HcnQueryEndpointProperties, HcnQueryNetworkProperties,
+            },
+            HostComputeSystem::{
+                HCS_OPERATION, HcsCloseOperation, HcsCreateOperation, HcsWaitForOperationResult,
+            },
+        },
+    },
+};
+
+use crate::error::Error;
+
+/// A string allocated by an HCN function, such as a result document or an error record.
+/// Freed with `CoTaskMemFree` when dropped.
+pub struct CoTaskMemString(PWSTR);
+
+impl CoTaskMemString {
+    pub fn new() -> Self {
+        CoTaskMemString(PWSTR::null())
+    }
+
+    /// Pointer for the function to write the string to.
+    pub fn as_out(&mut self) -> *mut PWSTR {
+        &mut self.0
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        unsafe { to_string_lossy(self.0) }
+    }
+}
+
+impl Drop for CoTaskMemString {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { CoTaskMemFree(Some(self.0.0 as *const c_void)) }
+        }
+    }
+}
+
+/// A string allocated by an HCS function. Freed with `LocalFree` when dropped.
+pub struct LocalString(PWSTR);
+
+impl LocalString {
+    pub fn new() -> Self {
+        LocalString(PWSTR::null())
+    }
+
+    /// Pointer for the function to write the string to.
+    pub fn as_out(&mut self) -> *mut PWSTR {
+        &mut self.0
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        unsafe { to_string_lossy(self.0) }
+    }
+}
+
+impl Drop for LocalString {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe {
+                LocalFree(Some(HLOCAL(self.0.0 as *mut c_void)));
+            }
+        }
+    }
+}
+
+unsafe fn to_string_lossy(s: PWSTR) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        unsafe { String::from_utf16_lossy(s.as_wide()) }
+    }
+}
+
+/// Describes a failed HCN call, including the error record it returned.
+pub fn hcn_error(message: &str, e: windows::core::Error, error_record: &CoTaskMemString) -> Error {
+    let record = error_record.to_string_lossy();
+
+    if record.trim().is_empty() {
+        Error::hcn(message, e)
+    } else {
+        Error::hcn(format!("{} {}", message, record.trim()), e)
+    }
+}
+
+/// An open HCN endpoint, closed when dropped.
+pub struct HcnEndpoint(*mut c_void);
+
+impl HcnEndpoint {
+    pub fn open(id: &GUID) -> Result<Self, Error> {
+        let mut handle: *mut c_void = std::ptr::null_mut();
+        let mut error_record = CoTaskMemString::new();
+
+        unsafe { HcnOpenEndpoint(id, &mut handle, Some(error_record.as_out())) }
+            .map_err(|e| hcn_error("Failed to open endpoint", e, &error_record))?;
+
+        Ok(HcnEndpoint(handle))
+    }
+
+    /// Returns the properties document of the endpoint.
+    pub fn query_properties(&self) -> Result<String, Error> {
+        let mut result_doc = CoTaskMemString::new();
+        let mut error_record = CoTaskMemString::new();
+
+        unsafe {
+            HcnQueryEndpointProperties(
+                self.0,
+                &HSTRING::new(),
+                result_doc.as_out(),
+                Some(error_record.as_out()),
+            )
+        }
+        .map_err(|e| hcn_error("Failed to query endpoint properties", e, &error_record))?;
+
+        Ok(result_doc.to_string_lossy())
+    }
+}
+
+impl Drop for HcnEndpoint {
+    fn drop(&mut self) {
+        let _ = unsafe { HcnCloseEndpoint(self.0) };
+    }
+}
+
+/// An open HCN network, closed when dropped.
+pub struct HcnNetwork(*mut c_void);
+
+impl HcnNetwork {
+    pub fn open(id: &GUID) -> Result<Self, Error> {
+        let mut handle: *mut c_void = std::ptr::null_mut();
+        let mut error_record = CoTaskMemString::new();
+
+        unsafe { HcnOpenNetwork(id, &mut handle, Some(error_record.as_out())) }
+            .map_err(|e| hcn_error("Failed to open network", e, &error_record))?;
+
+        Ok(HcnNetwork(handle))
+    }
+
+    /// Returns the properties document of the network.
+    pub fn query_properties(&self) -> Result<String, Error> {
+        let mut result_doc = CoTaskMemString::new();
+        let mut error_record = CoTaskMemString::new();
+
+        unsafe {
+            HcnQueryNetworkProperties(
+                self.0,
+                &HSTRING::new(),
+                result_doc.as_out(),
+                Some(error_record.as_out()),
+            )
+        }
+        .map_err(|e| hcn_error("Failed to query network properties", e, &error_record))?;
+
+        Ok(result_doc.to_string_lossy())
+    }
+}
+
+impl Drop for HcnNetwork {
+    fn drop(&mut self) {
+        let _ = unsafe { HcnCloseNetwork(self.0) };
+    }
+}
+
+/// An HCS operation, closed when dropped.
+pub struct HcsOperation(HCS_OPERATION);
+
+impl HcsOperation {
+    pub fn new() -> Result<Self, Error> {
+        let operation = unsafe { HcsCreateOperation(None, None) };
+
+        if operation.is_invalid() {
+            Err(Error::hcs("Failed to create an HCS operation", windows::core::Error::from_win32()))
+        } else {
+            Ok(HcsOperation(operation))
+        }
+    }
+
+    pub fn handle(&self) -> HCS_OPERATION {
+        self.0
+    }
+
+    /// Waits for the operation to complete and returns its result document. On failure the
+    /// result document describes the error.
+    pub fn wait(&self, timeout_ms: u32) -> Result<String, Error> {
+        let mut result_doc = LocalString::new();
+
+        match unsafe { HcsWaitForOperationResult(self.0, timeout_ms, Some(result_doc.as_out())) } {
+            Ok(()) => Ok(result_doc.to_string_lossy()),
+            Err(e) => {
+                let result = result_doc.to_string_lossy();
+
+                if result.trim().is_empty() {
+                    Err(Error::hcs("HCS operation failed", e))
+                } else {
+                    Err(Error::hcs(format!("HCS operation failed {}", result.trim()), e))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for HcsOperation {
+    fn drop(&mut self) {
+        unsafe { HcsCloseOperation(self.0) }
+    }
+}
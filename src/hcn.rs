@@ -1,93 +1,406 @@
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::error::Error;
+#[cfg(windows)]
+use log::warn;
+#[cfg(windows)]
 use windows::{
-    core::{GUID, HSTRING, PWSTR}, 
-    Win32::System::HostComputeNetwork::{HcnCloseEndpoint, HcnEnumerateEndpoints, HcnOpenEndpoint, HcnQueryEndpointProperties}
+    core::{GUID, HSTRING},
+    Win32::System::HostComputeNetwork::{HcnEnumerateEndpoints, HcnEnumerateNetworks},
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[cfg(windows)]
+use crate::handles::{CoTaskMemString, HcnEndpoint, HcnNetwork, hcn_error};
+
+/// An HCN endpoint, as returned by `HcnQueryEndpointProperties`. Every field is optional since
+/// endpoints that are not attached to a VM, such as host vNICs, leave most of them out. Both the
+/// V1 fields (`IPAddress`, `GatewayAddress`, ...) and the V2 ones (`IpConfigurations`, `Routes`, ...)
+/// are read, as the schema version depends on the version of Windows.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
 pub struct Endpoint {
     #[serde(rename = "ID")]
     pub id: String,
-    #[serde(rename = "Name")]
-    pub name: String,
-    #[serde(rename = "VirtualNetwork")]
-    pub virtual_network: String,
-    #[serde(rename = "VirtualNetworkName")]
-    pub virtual_network_name: String,
-    #[serde(rename = "IPAddress")] 
-    pub ipaddress:  String,
-    #[serde(rename = "GatewayAddress")]
-    pub gateway_address: String,
-    #[serde(rename = "VirtualMachine")] 
-    pub virtual_machine:  String,
-}
-
-pub fn list_endpoints() -> Result<Vec<Endpoint>, String> {
-   
+    pub name: Option<String>,
+    pub r#type: Option<String>,
+    pub state: Option<u32>,
+    pub flags: Option<u32>,
+    pub virtual_network: Option<String>,
+    pub virtual_network_name: Option<String>,
+    pub host_compute_network: Option<String>,
+    pub host_compute_namespace: Option<String>,
+    pub virtual_machine: Option<String>,
+    #[serde(rename = "IPAddress")]
+    pub ip_address: Option<String>,
+    pub prefix_length: Option<u8>,
+    pub gateway_address: Option<String>,
+    pub mac_address: Option<String>,
+    #[serde(rename = "DNSServerList")]
+    pub dns_server_list: Option<String>,
+    #[serde(rename = "DNSSuffix")]
+    pub dns_suffix: Option<String>,
+    pub dns: Option<Dns>,
+    pub ip_configurations: Vec<IpConfiguration>,
+    pub routes: Vec<EndpointRoute>,
+    pub policies: Vec<Policy>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct IpConfiguration {
+    pub ip_address: String,
+    pub prefix_length: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct EndpointRoute {
+    pub next_hop: Option<String>,
+    pub destination_prefix: Option<String>,
+    pub metric: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Dns {
+    pub domain: Option<String>,
+    pub search: Vec<String>,
+    pub server_list: Vec<String>,
+    pub options: Vec<String>,
+}
+
+/// An endpoint policy. The settings differ by type, so they are kept as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(rename = "Type")]
+    pub policy_type: String,
+    #[serde(flatten)]
+    pub settings: Map<String, Value>,
+}
+
+impl Endpoint {
+    /// The gateway of the endpoint: `GatewayAddress`, or the next hop of its default route.
+    pub fn gateway(&self) -> Option<IpAddr> {
+        self.gateway_address
+            .as_deref()
+            .and_then(|a| a.parse().ok())
+            .or_else(|| default_next_hop(&self.routes))
+    }
+
+    /// Returns true if the endpoint is attached to the VM, whose id is compared ignoring case.
+    pub fn is_attached_to(&self, vm_id: &str) -> bool {
+        self.virtual_machine
+            .as_deref()
+            .is_some_and(|id| id.eq_ignore_ascii_case(vm_id))
+    }
+}
+
+/// Names of the network that WSL attaches its VM to, depending on whether the Hyper-V firewall is enabled.
+pub const WSL_NETWORK_NAMES: [&str; 2] = ["WSL", "WSL (Hyper-V firewall)"];
+
+/// An HCN network, as returned by `HcnQueryNetworkProperties`. V1 schemas list `Subnets` with
+/// their gateway, V2 schemas list `Ipams` whose subnets have a default route instead.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Network {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub name: Option<String>,
+    pub r#type: Option<String>,
+    pub state: Option<u32>,
+    pub flags: Option<u32>,
+    #[serde(rename = "IPv6")]
+    pub ipv6: Option<bool>,
+    pub subnets: Vec<Subnet>,
+    pub ipams: Vec<Ipam>,
+    pub mac_pools: Vec<MacPool>,
+    pub policies: Vec<Policy>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Subnet {
+    pub address_prefix: Option<String>,
+    pub gateway_address: Option<String>,
+    pub ip_address_prefix: Option<String>,
+    pub routes: Vec<EndpointRoute>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Ipam {
+    pub r#type: Option<String>,
+    pub subnets: Vec<Subnet>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct MacPool {
+    pub start_mac_address: Option<String>,
+    pub end_mac_address: Option<String>,
+}
+
+impl Subnet {
+    /// The prefix of the subnet from `AddressPrefix` (V1) or `IpAddressPrefix` (V2).
+    pub fn prefix(&self) -> Option<IpNetwork> {
+        self.address_prefix
+            .as_deref()
+            .or(self.ip_address_prefix.as_deref())?
+            .parse()
+            .ok()
+    }
+
+    /// The gateway of the subnet: `GatewayAddress`, or the next hop of its default route.
+    pub fn gateway(&self) -> Option<IpAddr> {
+        self.gateway_address
+            .as_deref()
+            .and_then(|a| a.parse().ok())
+            .or_else(|| default_next_hop(&self.routes))
+    }
+}
+
+impl Network {
+    /// The subnets of the network from both schema versions.
+    pub fn all_subnets(&self) -> impl Iterator<Item = &Subnet> {
+        self.subnets
+            .iter()
+            .chain(self.ipams.iter().flat_map(|ipam| ipam.subnets.iter()))
+    }
+
+    /// The gateway of the first subnet that has one.
+    pub fn gateway(&self) -> Option<IpAddr> {
+        self.all_subnets().find_map(|s| s.gateway())
+    }
+
+    pub fn has_name(&self, name: &str) -> bool {
+        self.name
+            .as_deref()
+            .is_some_and(|n| n.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Returns the network with the first of the names that is found, compared ignoring case.
+pub fn select_network<'a>(networks: &'a [Network], names: &[&str]) -> Option<&'a Network> {
+    names
+        .iter()
+        .find_map(|name| networks.iter().find(|n| n.has_name(name)))
+}
+
+fn default_next_hop(routes: &[EndpointRoute]) -> Option<IpAddr> {
+    routes
+        .iter()
+        .find(|r| matches!(r.destination_prefix.as_deref(), Some("0.0.0.0/0") | Some("::/0")))?
+        .next_hop
+        .as_deref()?
+        .parse()
+        .ok()
+}
+
+pub fn parse_network(s: &str) -> Result<Network, Error> {
+    serde_json::from_str(s).map_err(|e| Error::parse("Failed to parse network", e))
+}
+
+pub fn parse_endpoint(s: &str) -> Result<Endpoint, Error> {
+    serde_json::from_str(s).map_err(|e| Error::parse("Failed to parse endpoint", e))
+}
+
+/// Lists every endpoint that can be read. Endpoints that cannot be opened or parsed are logged
+/// and skipped, so that one of them does not hide the others.
+#[cfg(windows)]
+pub fn list_endpoints() -> Result<Vec<Endpoint>, Error> {
     let ids = list_endpoint_ids()?;
-    let result : Result<Vec<Endpoint>, String> = ids.iter().map(|id|  {
-        let s = get_endpoint_properties(id.clone())?;
-        let network: Endpoint = serde_json::from_str(s.as_str()).map_err(|e| e.to_string())?;
-        Ok(network)
 
-    }).collect();
+    let endpoints = ids
+        .iter()
+        .filter_map(|id| {
+            match get_endpoint_properties(id.clone()).and_then(|s| parse_endpoint(&s)) {
+                Ok(endpoint) => Some(endpoint),
+                Err(e) => {
+                    warn!("Skipping endpoint {}: {}", id, e);
+                    None
+                }
+            }
+        })
+        .collect();
 
-    return result;
+    Ok(endpoints)
 }
 
-fn list_endpoint_ids() -> Result<Vec<String>, String> {
+#[cfg(windows)]
+fn list_endpoint_ids() -> Result<Vec<String>, Error> {
+    let mut result_doc = CoTaskMemString::new();
+    let mut error_record = CoTaskMemString::new();
+
     unsafe {
-        let mut result_doc: PWSTR = PWSTR(std::ptr::null_mut());
-        let mut error_record: PWSTR = PWSTR(std::ptr::null_mut());
         HcnEnumerateEndpoints(
-            &HSTRING::from(r#""#),
-            &mut result_doc,
-            Some(&mut error_record),
+            &HSTRING::new(),
+            result_doc.as_out(),
+            Some(error_record.as_out()),
         )
-        .map_err(|e| e.message())?;
+    }
+    .map_err(|e| hcn_error("Failed to enumerate endpoints", e, &error_record))?;
 
-        let result_string = if !result_doc.is_null() {
-            HSTRING::from_wide(result_doc.as_wide())
-        } else {
-            HSTRING::new()
-        };
+    serde_json::from_str(&result_doc.to_string_lossy())
+        .map_err(|e| Error::parse("Failed to parse endpoint ids", e))
+}
 
-        let s = result_string.to_string_lossy();
-        let network_ids: Vec<String> = serde_json::from_str(s.as_str()).unwrap();
+#[cfg(windows)]
+fn get_endpoint_properties(id: String) -> Result<String, Error> {
+    let endpoint_id = GUID::try_from(id.as_str())
+        .map_err(|e| Error::parse(format!("Invalid endpoint id {}", id), e))?;
 
-        Ok(network_ids)
-    }
+    HcnEndpoint::open(&endpoint_id)?.query_properties()
 }
 
-fn get_endpoint_properties(id: String) -> Result<String, String> {
-    unsafe {
-        let endpoint_id = GUID::try_from(id.as_str()).unwrap();
-        let mut endpoint_handle: *mut core::ffi::c_void = std::ptr::null_mut();
-        let mut error_record: PWSTR = PWSTR(std::ptr::null_mut());
+/// Lists every network that can be read. Networks that cannot be opened or parsed are logged
+/// and skipped.
+#[cfg(windows)]
+pub fn list_networks() -> Result<Vec<Network>, Error> {
+    let ids = list_network_ids()?;
+
+    let networks = ids
+        .iter()
+        .filter_map(|id| {
+            match get_network_properties(id.clone()).and_then(|s| parse_network(&s)) {
+                Ok(network) => Some(network),
+                Err(e) => {
+                    warn!("Skipping network {}: {}", id, e);
+                    None
+                }
+            }
+        })
+        .collect();
 
-        HcnOpenEndpoint(&endpoint_id, &mut endpoint_handle, Some(&mut error_record))
-            .map_err(|e| e.message())?;
+    Ok(networks)
+}
 
-        let mut result_doc: PWSTR = PWSTR(std::ptr::null_mut());
-        let mut error_record: PWSTR = PWSTR(std::ptr::null_mut());
+#[cfg(windows)]
+fn list_network_ids() -> Result<Vec<String>, Error> {
+    let mut result_doc = CoTaskMemString::new();
+    let mut error_record = CoTaskMemString::new();
 
-        HcnQueryEndpointProperties(
-            endpoint_handle,
-            &HSTRING::from(r#""#),
-            &mut result_doc,
-            Some(&mut error_record),
+    unsafe {
+        HcnEnumerateNetworks(
+            &HSTRING::new(),
+            result_doc.as_out(),
+            Some(error_record.as_out()),
         )
-        .map_err(|e| e.message())?;
+    }
+    .map_err(|e| hcn_error("Failed to enumerate networks", e, &error_record))?;
+
+    serde_json::from_str(&result_doc.to_string_lossy())
+        .map_err(|e| Error::parse("Failed to parse network ids", e))
+}
+
+#[cfg(windows)]
+fn get_network_properties(id: String) -> Result<String, Error> {
+    let network_id = GUID::try_from(id.as_str())
+        .map_err(|e| Error::parse(format!("Invalid network id {}", id), e))?;
+
+    HcnNetwork::open(&network_id)?.query_properties()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WSL_ENDPOINT: &str = include_str!("../tests/fixtures/hcn/endpoint-wsl.json");
+    const HOST_VNIC_ENDPOINT: &str = include_str!("../tests/fixtures/hcn/endpoint-host-vnic.json");
+    const V2_ENDPOINT: &str = include_str!("../tests/fixtures/hcn/endpoint-v2.json");
+
+    #[test]
+    fn wsl_endpoint_is_parsed() {
+        let endpoint = parse_endpoint(WSL_ENDPOINT).unwrap();
 
-        HcnCloseEndpoint(endpoint_handle).map_err(|e| e.message())?;
+        assert_eq!(endpoint.name.as_deref(), Some("Ethernet"));
+        assert_eq!(endpoint.virtual_network_name.as_deref(), Some("WSL"));
+        assert_eq!(endpoint.gateway(), Some("172.20.0.1".parse().unwrap()));
+        assert_eq!(endpoint.ip_address.as_deref(), Some("172.20.5.12"));
+        assert_eq!(endpoint.prefix_length, Some(20));
+        assert!(endpoint.is_attached_to("8b4f2a8e-6c1d-4e5a-9a3b-1d2c3e4f5a6b"));
+        assert_eq!(endpoint.policies[0].policy_type, "L2Driver");
+    }
+
+    #[test]
+    fn endpoint_without_a_vm_is_parsed() {
+        let endpoint = parse_endpoint(HOST_VNIC_ENDPOINT).unwrap();
+
+        assert_eq!(endpoint.virtual_machine, None);
+        assert_eq!(endpoint.gateway(), None);
+        assert!(!endpoint.is_attached_to("8b4f2a8e-6c1d-4e5a-9a3b-1d2c3e4f5a6b"));
+    }
+
+    #[test]
+    fn v2_endpoint_takes_its_gateway_from_the_default_route() {
+        let endpoint = parse_endpoint(V2_ENDPOINT).unwrap();
+
+        assert_eq!(endpoint.gateway(), Some("172.28.16.1".parse().unwrap()));
+        assert_eq!(endpoint.ip_configurations[1].ip_address, "fd00:28::5");
+        assert_eq!(endpoint.mac_address.as_deref(), Some("00-15-5D-C4-D5-E6"));
+        assert_eq!(endpoint.dns.unwrap().server_list, vec!["172.28.16.1"]);
+        assert_eq!(endpoint.flags, Some(2));
+        assert_eq!(
+            endpoint.policies[0].settings["Settings"]["InternalPort"],
+            Value::from(8080)
+        );
+    }
+
+    #[test]
+    fn malformed_endpoint_is_an_error() {
+        assert!(parse_endpoint(r#"{ "ID": "1", "IPAddress": 42 }"#).is_err());
+    }
+
+    const WSL_NETWORK: &str = include_str!("../tests/fixtures/hcn/network-wsl.json");
+    const WSL_FIREWALL_NETWORK: &str = include_str!("../tests/fixtures/hcn/network-wsl-firewall-v2.json");
+    const DEFAULT_SWITCH_NETWORK: &str = include_str!("../tests/fixtures/hcn/network-default-switch.json");
+
+    fn networks() -> Vec<Network> {
+        [DEFAULT_SWITCH_NETWORK, WSL_FIREWALL_NETWORK, WSL_NETWORK]
+            .iter()
+            .map(|s| parse_network(s).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn v1_network_has_a_subnet_and_gateway() {
+        let network = parse_network(WSL_NETWORK).unwrap();
+
+        assert_eq!(network.all_subnets().count(), 1);
+        assert_eq!(
+            network.subnets[0].prefix(),
+            Some("172.20.0.0/20".parse().unwrap())
+        );
+        assert_eq!(network.gateway(), Some("172.20.0.1".parse().unwrap()));
+        assert_eq!(network.mac_pools.len(), 1);
+    }
+
+    #[test]
+    fn v2_network_takes_its_gateway_from_the_ipam_routes() {
+        let network = parse_network(WSL_FIREWALL_NETWORK).unwrap();
 
-        let result_string = if !result_doc.is_null() {
-            HSTRING::from_wide(result_doc.as_wide())
-        } else {
-            HSTRING::new()
-        };
+        let prefixes: Vec<IpNetwork> = network.all_subnets().filter_map(|s| s.prefix()).collect();
 
-        let s = result_string.to_string_lossy();
-        Ok(s)
+        assert_eq!(
+            prefixes,
+            vec!["172.28.16.0/20".parse().unwrap(), "fd00:28::/64".parse().unwrap()]
+        );
+        assert_eq!(network.gateway(), Some("172.28.16.1".parse().unwrap()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn wsl_network_is_selected_by_name() {
+        let networks = networks();
+
+        let network = select_network(&networks, &WSL_NETWORK_NAMES).unwrap();
+        assert_eq!(network.name.as_deref(), Some("WSL"));
+
+        let network = select_network(&networks, &["wsl (hyper-v firewall)"]).unwrap();
+        assert_eq!(network.name.as_deref(), Some("WSL (Hyper-V firewall)"));
+
+        assert!(select_network(&networks, &["nat"]).is_none());
+    }
+}
@@ -1,46 +1,45 @@
 use serde::{Deserialize, Serialize};
-use windows::{
-    core::{HSTRING, PWSTR}, 
-    Win32::System::HostComputeSystem::{HcsCloseOperation, HcsCreateOperation, HcsEnumerateComputeSystems, HcsWaitForOperationResult}
-};
+use windows::{core::HSTRING, Win32::System::HostComputeSystem::HcsEnumerateComputeSystems};
 
-pub fn get_virtual_machine_id(owner: &str) -> Result<String, String> {
+use crate::{error::Error, handles::HcsOperation};
+
+/// Returns the ids of every compute system of the owner, or of the one with the given name or id.
+pub fn get_virtual_machine_ids(owner: &str, name: Option<&str>) -> Result<Vec<String>, Error> {
 
     #[derive(Debug, Serialize, Deserialize)]
     struct ComputeSystem {
         #[serde(rename = "Id")]
         id: String,
+        #[serde(rename = "Name", default)]
+        name: String,
     }
 
-    unsafe {
-        let operation = HcsCreateOperation(None, None);
-        let query_hstring = HSTRING::from(format!(r#"{{"Owners": ["{}"]}}"#, owner));
-        
-        HcsEnumerateComputeSystems(&query_hstring, operation.clone())
-            .map_err(|e| e.to_string())?;
-
-        let mut result_doc: PWSTR = PWSTR(std::ptr::null_mut());
+    #[derive(Serialize)]
+    struct Query<'a> {
+        #[serde(rename = "Owners")]
+        owners: [&'a str; 1],
+    }
 
-        HcsWaitForOperationResult(operation, u32::MAX, Some(&mut result_doc))
-            .map_err(|e| e.to_string())?;
+    let query = serde_json::to_string(&Query { owners: [owner] })
+        .map_err(|e| Error::parse("Failed to serialize compute system query", e))?;
+    let operation = HcsOperation::new()?;
 
-        let result_string = if !result_doc.is_null() {
-            HSTRING::from_wide(result_doc.as_wide())
-        } else {
-            HSTRING::new()
-        };
+    unsafe { HcsEnumerateComputeSystems(&HSTRING::from(query), operation.handle()) }
+        .map_err(|e| Error::hcs("Failed to enumerate compute systems", e))?;
 
-        HcsCloseOperation(operation);
+    let s = operation.wait(u32::MAX)?;
 
-        let s = result_string.to_string_lossy();
+    let compute_systems: Vec<ComputeSystem> = serde_json::from_str(s.as_str())
+        .map_err(|e| Error::parse("Failed to parse compute systems", e))?;
 
-        let compute_systems: Vec<ComputeSystem> = serde_json::from_str(s.as_str())
-            .map_err(|e| e.to_string())?;
+    let compute_systems: Vec<ComputeSystem> = compute_systems
+        .into_iter()
+        .filter(|x| name.is_none_or(|n| x.name.eq_ignore_ascii_case(n) || x.id.eq_ignore_ascii_case(n)))
+        .collect();
 
-        if compute_systems.is_empty() {
-            Err(String::from(format!("Could not find virtual machine for {}", owner)))
-        } else {
-            Ok(compute_systems[0].id.clone())
-        }
+    if compute_systems.is_empty() {
+        Err(Error::not_found(format!("Could not find virtual machine for {}{}", owner, name.map(|n| format!(" named {}", n)).unwrap_or_default())))
+    } else {
+        Ok(compute_systems.into_iter().map(|x| x.id).collect())
     }
 }
@@ -1,32 +1,57 @@
-use std::{ffi::OsString, path::PathBuf, thread, time::Duration};
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
 
-use ipnetwork::Ipv4Network;
-use log::LevelFilter;
 use windows_service::{
     service::{
-        ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceState,
+        Service, ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceState,
         ServiceType,
     },
     service_manager::{ServiceManager, ServiceManagerAccess},
 };
 
 use clap::Parser;
-use cli::{Cli, Commands};
+use serde::Serialize;
+
+use crate::{
+    cli::{Cli, Commands, RunArgs, RunCommandArgs},
+    config::{Config, default_config_path},
+    control::{self, Request},
+    error::Error,
+    output::{Output, Report},
+    routes::{IpHelperRouteTable, RouteSpec, RouteTable, remove_routes},
+    state::{load_managed_routes, managed_routes_path, save_managed_routes},
+    status::{Status, vm_status},
+    wsl_monitor::DetectionOptions,
+};
 
-use crate::{cli};
+pub fn install_service(service_name: &str, args: RunArgs, output: &mut Output) -> Result<(), Error> {
+    let config = args.to_config()?;
 
-pub fn install_service(
-    service_name: &str,
-    wsl_interface: Option<String>,
-    routes: Vec<String>,
-    log_level: LevelFilter
-) -> Result<(), String> {
+    let config_path = match args.config {
+        Some(path) => path,
+        None => {
+            let path = default_config_path();
+            config.save(&path)?;
+            output.message(format!("Configuration written to {}", path.display()));
+            path
+        }
+    };
+
+    // The service does not run from the current directory
+    let config_path = std::path::absolute(&config_path)
+        .map_err(|e| Error::config(format!("Invalid config path {}", config_path.display())).with_source(e))?;
 
     let manager_access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
     let service_manager =
         ServiceManager::local_computer(None::<&str>, manager_access).map_win_err()?;
 
-    let service_binary_path = ::std::env::current_exe().unwrap();
+    let service_binary_path = ::std::env::current_exe()
+        .map_err(|e| Error::io("Failed to find the path of the route2wsl executable", e))?;
 
     let service_info = ServiceInfo {
         name: OsString::from(service_name),
@@ -35,7 +60,7 @@ pub fn install_service(
         start_type: ServiceStartType::AutoStart,
         error_control: ServiceErrorControl::Normal,
         executable_path: service_binary_path,
-        launch_arguments: build_cmdline_args(wsl_interface, routes, log_level),
+        launch_arguments: build_cmdline_args(&config_path),
         dependencies: vec![],
         account_name: None, // run as System
         account_password: None,
@@ -48,20 +73,23 @@ pub fn install_service(
         )
         .map_win_err()?;
 
-    service.set_description("Configures rules in the IPv4 routing table to forward specific IP traffic through WSL")
+    service.set_description("Configures rules in the IPv4 and IPv6 routing tables to forward specific IP traffic through WSL")
         .map_win_err()?;
 
-    println!("Service installed!");
+    output.message("Service installed!");
 
-    println!("Starting service");
+    output.message("Starting service");
     service.start(&[OsString::from("Starting from installer")]).map_win_err()?;
-    println!("Service started");
+    output.message("Service started");
 
     Ok(())
 }
 
-pub fn uninstall_service(service_name: &str) -> Result<(), String> {
-    println!("Uninstalling service");
+pub fn uninstall_service(service_name: &str, output: &mut Output) -> Result<(), Error> {
+    output.message("Uninstalling service");
+
+    // Only the executable and state file are needed to clean up, so a broken config doesn't stop it
+    let installed_command = get_installed_command(service_name);
 
     let manager_access = ServiceManagerAccess::CONNECT;
     let service_manager =
@@ -78,83 +106,200 @@ pub fn uninstall_service(service_name: &str) -> Result<(), String> {
     // Our handle to it is not closed yet. So we can still query it.
     if match service.query_status() {
         Ok(it) => it,
-        Err(err) => return Err(format!("Failed to get service status: {}", err)),
+        Err(err) => return Err(Error::scm("Failed to get service status", err)),
     }
     .current_state != ServiceState::Stopped
     {
-        let _ = service
-            .stop()
-            .inspect_err(|e| println!("Failed to stop service: {}", e));
+        match service.stop() {
+            Ok(_) => wait_for_stop(&service)?,
+            Err(e) => output.message(format!("Failed to stop service: {}", e)),
+        }
     }
 
-    println!("{} is marked for deletion.", service_name);
+    output.message(format!("{} is marked for deletion.", service_name));
+
+    // The service removes its routes when it stops, unless it was told to keep them
+    match installed_command {
+        Ok((executable, args)) => {
+            remove_managed_routes(&executable, output)?;
+            remove_default_config(args.config)?;
+        }
+        Err(e) => output.message(format!("Skipped removing routes: {}", e)),
+    }
 
     Ok(())
 }
 
-pub fn print_installation_details(service_name: &str) -> Result<(), String> {
-   let existing_installation = get_existing_installation_details(service_name)?;
+fn remove_managed_routes(executable: &str, output: &mut Output) -> Result<(), Error> {
+    let Some(install_dir) = Path::new(executable).parent() else {
+        return Ok(());
+    };
 
-   println!("Instaled Path: {}", existing_installation.executable);
-   println!("With Routes:");
-   for route in existing_installation.routes {
-     println!("   {route}")
-   }
+    let state_file = managed_routes_path(install_dir);
+    let mut managed_routes = load_managed_routes(&state_file)?;
 
-    Ok(())
+    if managed_routes.is_empty() {
+        return Ok(());
+    }
+
+    output.message(format!("Removing {} route(s)", managed_routes.len()));
+    let removed = remove_routes(&mut IpHelperRouteTable, &mut managed_routes);
+    save_managed_routes(&state_file, &managed_routes)?;
+
+    removed
 }
 
-pub fn add_route(service_name: &str, new_routes: Vec<Ipv4Network>) -> Result<(), String> {
-    let InstallationDetails { executable, wsl_interface, routes, log_level } = get_existing_installation_details(service_name)?;
-    let mut updated_routes = routes;
+fn remove_default_config(config_path: Option<PathBuf>) -> Result<(), Error> {
+    let default_path = default_config_path();
 
-    for route in new_routes {
-        if !updated_routes.contains(&route) {
-            updated_routes.push(route);
-        }
+    // A config file supplied by the user is left alone
+    if config_path.as_ref() != Some(&default_path) || !default_path.exists() {
+        return Ok(());
     }
 
-    println!("Updating service with new routes");
-    update_service(service_name, executable, wsl_interface, updated_routes.iter().map(|n| n.to_string()).collect(), log_level)?;
+    std::fs::remove_file(&default_path)
+        .map_err(|e| Error::io(format!("Failed to remove {}", default_path.display()), e))
+}
 
-    Ok(())
+/// Returns the details of the existing installation.
+pub fn inspect_installation(service_name: &str) -> Result<InstallationDetails, Error> {
+    get_existing_installation_details(service_name)
 }
 
-fn build_cmdline_args(wsl_interface: Option<String>, routes: Vec<String>, log_level: LevelFilter) -> Vec<OsString> {
-    let mut args = vec![
-        OsString::from("run")
-    ];
+/// Returns the state of the installed service and checks the routes of its VMs against the routing table.
+pub fn service_status(service_name: &str) -> Result<Status, Error> {
+    let installation = get_existing_installation_details(service_name)?;
+    let config = &installation.config;
 
-    if let Some(val) = wsl_interface {
-        args.extend([OsString::from("--wsl-interface"), OsString::from(val.clone())]);
-    }
+    let manager_access = ServiceManagerAccess::CONNECT;
+    let service_manager =
+        ServiceManager::local_computer(None::<&str>, manager_access).map_win_err()?;
+
+    let service = service_manager
+        .open_service(service_name, ServiceAccess::QUERY_STATUS)
+        .map_win_err()?;
+
+    let service_state = service.query_status().map_win_err()?.current_state;
+    let actual = IpHelperRouteTable.list()?;
+
+    // The running service tells what it did in its last pass over the routing table
+    let (monitor, monitor_error) = if service_state == ServiceState::Running {
+        match control::request(&Request::Status).and_then(|status| {
+            serde_json::from_value(status).map_err(|e| Error::parse("Invalid status in the response of the service", e))
+        }) {
+            Ok(monitor) => (Some(monitor), None),
+            Err(e) => (None, Some(e.report())),
+        }
+    } else {
+        (None, None)
+    };
 
-    args.extend(
-        routes
+    Ok(Status {
+        service_state: service_state.into(),
+        monitor,
+        monitor_error,
+        vms: config
+            .vm_routes()
             .iter()
-            .flat_map(|route| vec![OsString::from("-r"), OsString::from(route)]),
-    );
+            .map(|vm| vm_status(vm, &DetectionOptions::from(config), config.metric, &actual))
+            .collect(),
+    })
+}
+
+pub fn add_route(
+    service_name: &str,
+    new_routes: Vec<RouteSpec>,
+    output: &mut Output,
+) -> Result<Vec<RouteSpec>, Error> {
+    let mut installation = get_existing_installation_details(service_name)?;
+    installation.config.add_routes(new_routes.clone());
+    installation.config.validate()?;
+
+    output.message("Updating service with new routes");
+    update_service(service_name, installation, Request::AddRoutes { routes: new_routes }, output)
+}
+
+pub fn remove_route(
+    service_name: &str,
+    old_routes: Vec<RouteSpec>,
+    output: &mut Output,
+) -> Result<Vec<RouteSpec>, Error> {
+    let mut installation = get_existing_installation_details(service_name)?;
+
+    for route in installation.config.remove_routes(&old_routes) {
+        output.message(format!("Route {} is not configured", route.destination));
+    }
+
+    installation.config.validate()?;
 
-    args.extend([OsString::from("--log-level"), OsString::from(log_level.to_string())]);
-    args
+    output.message("Updating service with removed routes");
+    update_service(service_name, installation, Request::RemoveRoutes { routes: old_routes }, output)
 }
 
+pub fn set_routes(
+    service_name: &str,
+    routes: Vec<RouteSpec>,
+    output: &mut Output,
+) -> Result<Vec<RouteSpec>, Error> {
+    let mut installation = get_existing_installation_details(service_name)?;
+    installation.config.routes.clear();
+    installation.config.add_routes(routes.clone());
+    installation.config.validate()?;
+
+    output.message("Updating service with new routes");
+    update_service(service_name, installation, Request::SetRoutes { routes }, output)
+}
+
+fn build_cmdline_args(config_path: &Path) -> Vec<OsString> {
+    vec![
+        OsString::from("run"),
+        OsString::from("--config"),
+        config_path.as_os_str().to_os_string(),
+    ]
+}
+
+/// Has the running service make the route change to its configuration file, so it can't be lost to
+/// a change the service makes at the same time. Otherwise the changed configuration is saved and the
+/// service started. Installations that pass their options on the command line are moved to a
+/// configuration file at the default location and restarted.
 fn update_service(
     service_name: &str,
-    executable: String,
-    wsl_interface: Option<String>,
-    routes: Vec<String>,
-    log_level: LevelFilter
-) -> Result<(), String> {
+    installation: InstallationDetails,
+    request: Request,
+    output: &mut Output,
+) -> Result<Vec<RouteSpec>, Error> {
     let manager_access = ServiceManagerAccess::CONNECT;
     let service_manager =
         ServiceManager::local_computer(None::<&str>, manager_access).map_win_err()?;
 
-    let service_access = ServiceAccess::QUERY_STATUS | ServiceAccess::QUERY_CONFIG | ServiceAccess::STOP | ServiceAccess::START | ServiceAccess::CHANGE_CONFIG;
+    let service_access = ServiceAccess::QUERY_STATUS | ServiceAccess::QUERY_CONFIG | ServiceAccess::STOP | ServiceAccess::START | ServiceAccess::CHANGE_CONFIG | ServiceAccess::PAUSE_CONTINUE;
     let service = service_manager
         .open_service(service_name, service_access)
         .map_win_err()?;
 
+    let service_status = service.query_status().map_win_err()?;
+
+    // Services installed with a configuration file pick up the changes while running
+    if let Some(config_path) = installation.config_path {
+        if service_status.current_state == ServiceState::Running {
+            let routes = control::request(&request)?;
+            output.message("Service configuration updated");
+
+            return serde_json::from_value(routes)
+                .map_err(|e| Error::parse("Invalid routes in the response of the service", e));
+        }
+
+        installation.config.save(&config_path)?;
+        service.start(&[OsString::from("Updated from installer")]).map_win_err()?;
+        output.message("Service started");
+
+        return Ok(installation.config.routes);
+    }
+
+    // Services installed before configuration files need new launch arguments and a restart
+    let config_path = default_config_path();
+    installation.config.save(&config_path)?;
+
     let current_config = service.query_config().map_win_err()?;
     
     let updated_service_info = ServiceInfo {
@@ -163,8 +308,8 @@ fn update_service(
         service_type: current_config.service_type,
         start_type: current_config.start_type,
         error_control: ServiceErrorControl::Normal,
-        executable_path: PathBuf::from(executable),
-        launch_arguments: build_cmdline_args(wsl_interface, routes, log_level),
+        executable_path: PathBuf::from(&installation.executable),
+        launch_arguments: build_cmdline_args(&config_path),
         dependencies: current_config.dependencies,
         account_name: None, // run as System
         account_password: None,
@@ -172,32 +317,47 @@ fn update_service(
 
     service.change_config(&updated_service_info).map_win_err()?;
 
-    println!("Restarting service");
-
-    let service_status = service.query_status().map_win_err()?;
+    output.message("Restarting service");
 
     // Stop the service if it's running
     if service_status.current_state != ServiceState::Stopped {
         service.stop().map_win_err()?;
-        
-        // Wait for service to stop (with timeout)
-        let mut attempts = 0;
-        while service.query_status().map_win_err()?.current_state != ServiceState::Stopped {
-            thread::sleep(Duration::from_secs(1));
-            attempts += 1;
-            if attempts > 30 {
-                return Err("Timeout waiting for service to stop".into());
-            }
-        }
+        wait_for_stop(&service)?;
     }
 
     service.start(&[OsString::from("Updated from installer")]).map_win_err()?;
-    println!("Service restarted");
+    output.message("Service restarted");
+
+    Ok(installation.config.routes)
+}
+
+fn wait_for_stop(service: &Service) -> Result<(), Error> {
+    // Wait for service to stop (with timeout)
+    let mut attempts = 0;
+    while service.query_status().map_win_err()?.current_state != ServiceState::Stopped {
+        thread::sleep(Duration::from_secs(1));
+        attempts += 1;
+        if attempts > 30 {
+            return Err(Error::scm("Timeout waiting for service to stop", io::Error::from(io::ErrorKind::TimedOut)));
+        }
+    }
 
     Ok(())
 }
 
-fn get_existing_installation_details(service_name: &str) -> Result<InstallationDetails, String> {
+fn get_existing_installation_details(service_name: &str) -> Result<InstallationDetails, Error> {
+    let (executable, args) = get_installed_command(service_name)?;
+
+    Ok(InstallationDetails {
+        executable,
+        config: args.to_config()?,
+        config_path: args.config,
+    })
+}
+
+/// The executable and the arguments the service was installed with. Unlike the installation
+/// details, this does not need the configuration to be valid.
+fn get_installed_command(service_name: &str) -> Result<(String, RunArgs), Error> {
     let manager_access = ServiceManagerAccess::CONNECT;
     let service_manager =
         ServiceManager::local_computer(None::<&str>, manager_access).map_win_err()?;
@@ -217,50 +377,77 @@ fn get_existing_installation_details(service_name: &str) -> Result<InstallationD
             args.extend(path_and_args.enumerate().map(|x| x.1));
 
             let cli: Cli = Cli::try_parse_from(args)
-                .map_err(|e| format!("Service was installed with unknown arguments: {}", e))?;
-
-            if let Commands::Run(cli::RunArgs {
-                wsl_interface,
-                routes,
-                log_level,
-            }) = cli.command
-            {
-                return Ok(InstallationDetails {
-                    executable,
-                    wsl_interface: wsl_interface,
-                    routes,
-                    log_level: log_level,
-                });
+                .map_err(|e| Error::parse("Service was installed with unknown arguments", e))?;
+
+            if let Commands::Run(RunCommandArgs { run: args, .. }) = cli.command {
+                return Ok((executable, args));
             }
         }
     }
 
-    return Err("A valid installation could not be found".into());
+    Err(Error::not_found("A valid installation could not be found"))
+}
 
+#[derive(Serialize)]
+pub struct InstallationDetails {
+    pub executable: String,
+    /// `None` for installations that pass their options on the command line
+    pub config_path: Option<PathBuf>,
+    pub config: Config,
 }
 
-struct InstallationDetails {
-    executable: String,
-    pub wsl_interface: Option<String>,
-    pub routes: Vec<Ipv4Network>,
-    pub log_level: LevelFilter 
+impl Report for InstallationDetails {
+    fn text(&self) -> String {
+        let config = &self.config;
+        let mut lines = vec![format!("Installed Path: {}", self.executable)];
+
+        match &self.config_path {
+            Some(path) => lines.push(format!("Configuration: {}", path.display())),
+            None => lines.push(String::from("Configuration: service command line")),
+        }
+
+        lines.push(format!("VM Owner: {}", config.vm_owner));
+        lines.push(format!("VM Name: {}", config.vm_name.as_deref().unwrap_or("any")));
+        lines.push(format!("WSL Interface: {}", config.wsl_interface.as_deref().unwrap_or("auto detect")));
+        lines.push(format!("Networking Mode: {}", config.networking_mode));
+
+        if let Some(guest_address) = config.wsl_guest_address {
+            lines.push(format!("WSL Guest Address: {}", guest_address));
+        }
+
+        lines.push(format!("Log Level: {}", config.log_level));
+        lines.push(format!("Log Directory: {}", config.log_dir().display()));
+        lines.push(format!("Log Format: {:?}", config.log_format));
+        lines.push(format!(
+            "Log Rotation: {} MB, {} files, {} days",
+            config.log_max_size, config.log_max_files, config.log_max_age
+        ));
+        lines.push(format!("Poll Interval: {}s", config.poll_interval));
+        lines.push(format!("Metric: {}", config.metric));
+        lines.push(format!("Keep Routes On Stop: {}", config.keep_routes_on_stop));
+        lines.push(String::from("With Routes:"));
+        lines.extend(config.routes.iter().map(|route| format!("   {route}")));
+
+        for vm in &config.vms {
+            lines.push(format!("With Routes Through {}:", vm.vm()));
+            lines.extend(vm.routes.iter().map(|route| format!("   {route}")));
+        }
+
+        lines.join("\n")
+    }
 }
 
 trait ErrorExt<T> {
-    fn map_win_err(self) -> Result<T, String>;
+    fn map_win_err(self) -> Result<T, Error>;
 }
 
 impl<T> ErrorExt<T> for Result<T, windows_service::Error> {
-    fn map_win_err(self) -> Result<T, String> {
-        match self {
-            Ok(t) => Ok(t),
-            Err(e) => Err((|e| {
-                format!("{}", match e {
-                        windows_service::Error::Winapi(error) => error.to_string(),
-                        _ => e.to_string(),
-                    }
-                )
-            })(e)),
-        }
+    fn map_win_err(self) -> Result<T, Error> {
+        self.map_err(|e| match e {
+            windows_service::Error::Winapi(error) => {
+                Error::scm("Service Control Manager call failed", error)
+            }
+            _ => Error::scm("Service Control Manager call failed", e),
+        })
     }
 }
\ No newline at end of file
@@ -0,0 +1,63 @@
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+use network_interface::NetworkInterface;
+#[cfg(windows)]
+use network_interface::NetworkInterfaceConfig;
+
+#[cfg(windows)]
+use crate::error::Error;
+
+/// A network interface of the host, with the parts that detection and routing use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostInterface {
+    pub name: String,
+    pub index: u32,
+    pub addresses: Vec<InterfaceAddress>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub ip: IpAddr,
+    pub netmask: Option<IpAddr>,
+}
+
+impl HostInterface {
+    pub fn has_address(&self, ip: IpAddr) -> bool {
+        self.addresses.iter().any(|a| a.ip == ip)
+    }
+
+    /// Whether the address is in the subnet of one of the addresses of the interface.
+    pub fn is_on_network_of(&self, address: IpAddr) -> bool {
+        self.addresses.iter().any(|a| {
+            a.netmask
+                .and_then(|netmask| IpNetwork::with_netmask(a.ip, netmask).ok())
+                .is_some_and(|network| network.contains(address))
+        })
+    }
+}
+
+impl From<&NetworkInterface> for HostInterface {
+    fn from(interface: &NetworkInterface) -> Self {
+        HostInterface {
+            name: interface.name.clone(),
+            index: interface.index,
+            addresses: interface
+                .addr
+                .iter()
+                .map(|a| InterfaceAddress {
+                    ip: a.ip(),
+                    netmask: a.netmask(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The network interfaces of the host.
+#[cfg(windows)]
+pub fn list() -> Result<Vec<HostInterface>, Error> {
+    let interfaces = NetworkInterface::show().map_err(|e| Error::io("Failed to get network adapters", e))?;
+
+    Ok(interfaces.iter().map(HostInterface::from).collect())
+}
@@ -1,36 +1,370 @@
-use std::{env, fs, panic};
-use chrono::Local;
-use fern::Dispatch;
-use log::{error, LevelFilter};
+use std::{
+    fmt::Arguments,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+#[cfg(windows)]
+use std::panic;
+use chrono::{Local, SecondsFormat};
+use clap::ValueEnum;
+use fern::{Dispatch, FormatCallback};
+use log::{
+    kv::{self, Key, Value as KvValue, VisitSource, VisitValue},
+    Record,
+};
+#[cfg(windows)]
+use log::{error, warn, LevelFilter};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 
-pub fn init_service_logger(log_level: LevelFilter) -> Result<(), fern::InitError> {
+#[cfg(windows)]
+use crate::{config::default_log_dir, security};
 
-    let logs_dir = env::current_exe()?.parent().unwrap().join("logs");
-    let logs_file = logs_dir.clone().join("route2wsl.log");
+pub const DEFAULT_LOG_MAX_SIZE_MB: u64 = 10;
+pub const DEFAULT_LOG_MAX_FILES: u32 = 5;
+pub const DEFAULT_LOG_MAX_AGE_DAYS: u64 = 7;
 
-    fs::create_dir_all(logs_dir)?;
+#[cfg(windows)]
+const LOG_FILE_NAME: &str = "route2wsl.log";
 
+/// How log records are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `[timestamp] [LEVEL]: message` lines
+    #[default]
+    Text,
+    /// One JSON object per line with the timestamp, level, target, message and the fields of the record
+    Json,
+}
+
+impl LogFormat {
+    pub fn is_text(&self) -> bool {
+        *self == LogFormat::Text
+    }
+
+    fn formatter(self) -> fn(FormatCallback, &Arguments, &Record) {
+        match self {
+            LogFormat::Text => format_record,
+            LogFormat::Json => format_json_record,
+        }
+    }
+}
+
+/// When the log file is rotated and how many rotated files are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogRotation {
+    /// The file is rotated before it grows past this many bytes.
+    pub max_size: u64,
+    /// Rotated files kept as `route2wsl.log.1` (the newest) to `route2wsl.log.<max_files>`.
+    pub max_files: u32,
+    /// The file is rotated once it is older than this. Zero only rotates by size.
+    pub max_age: Duration,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        LogRotation {
+            max_size: DEFAULT_LOG_MAX_SIZE_MB * 1024 * 1024,
+            max_files: DEFAULT_LOG_MAX_FILES,
+            max_age: Duration::from_secs(DEFAULT_LOG_MAX_AGE_DAYS * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Logs to `route2wsl.log` in `logs_dir`, creating the directory if needed.
+#[cfg(windows)]
+pub fn init_service_logger(log_level: LevelFilter, logs_dir: &Path, rotation: LogRotation, format: LogFormat) -> Result<(), fern::InitError> {
+
+    let logs_file = logs_dir.join(LOG_FILE_NAME);
+
+    create_logs_dir(logs_dir)?;
+
+    let log_file: Box<dyn Write + Send> = Box::new(RotatingFile::open(logs_file, rotation)?);
+
+    // Everything passes the dispatcher so the level can be changed on a configuration reload
     Dispatch::new()
-        .level(log_level)
+        .level(LevelFilter::Trace)
         .chain(Dispatch::new()
-        .format(move |out, message, record| {
-           let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S.%3f").to_string();
-           let level = record.level();
-           let msg = message;
-
-           out.finish(format_args!(
-               "[{}] [{}]: {}",
-               timestamp, level, msg
-           ))
-        })
-        .chain( fern::log_file(logs_file)?))
+        .format(format.formatter())
+        .chain(log_file))
         .apply()?;
 
+    log::set_max_level(log_level);
     set_panic_hook();
 
+    match security::writable_by_users(logs_dir) {
+        Ok(false) => {}
+        Ok(true) => warn!("Users can write to {}, so they can tamper with the logs", logs_dir.display()),
+        Err(e) => warn!("Failed to check who can write to {}: {}", logs_dir.display(), e),
+    }
+
     Ok(())
 }
 
+/// Logs to stderr, for running in the foreground.
+#[cfg(windows)]
+pub fn init_console_logger(log_level: LevelFilter, format: LogFormat) -> Result<(), fern::InitError> {
+    Dispatch::new()
+        .level(LevelFilter::Trace)
+        .chain(Dispatch::new()
+        .format(format.formatter())
+        .chain(io::stderr()))
+        .apply()?;
+
+    log::set_max_level(log_level);
+
+    Ok(())
+}
+
+/// Creates the logs directory so that only SYSTEM and Administrators can write to it, while users
+/// can read the logs. The default directory and `%ProgramData%\route2wsl` get the same security
+/// when they already exist, so that a user who created them first can't tamper with the logs. Other
+/// directories that already exist may be shared with other software and are left as they are. Log
+/// files that already exist are always restricted.
+#[cfg(windows)]
+fn create_logs_dir(dir: &Path) -> io::Result<()> {
+    if dir == default_log_dir() {
+        if let Some(parent) = dir.parent() {
+            security::create_dir_all(parent)?;
+            security::restrict(parent)?;
+        }
+
+        security::create_dir_all(dir)?;
+        security::restrict(dir)?;
+    } else {
+        security::create_dir_all(dir)?;
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(LOG_FILE_NAME)) {
+            security::restrict(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A log file that is moved to `<path>.1` when it grows too large or too old, shifting the older
+/// files up and deleting the ones past `max_files`.
+///
+/// A record reaches the writer in several writes, so writes are buffered until the end of a line
+/// or a flush and only then written, after rotating if needed. A record is never split across files.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    file: File,
+    size: u64,
+    created: SystemTime,
+    pending: Vec<u8>,
+}
+
+impl RotatingFile {
+    /// Opens the file for appending and deletes rotated files past `max_files`.
+    pub fn open(path: PathBuf, rotation: LogRotation) -> io::Result<RotatingFile> {
+        let existed = path.exists();
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let now = SystemTime::now();
+
+        if !existed {
+            set_created(&file, now)?;
+        }
+
+        let mut index = rotation.max_files + 1;
+        while rotated_path(&path, index).exists() {
+            fs::remove_file(rotated_path(&path, index))?;
+            index += 1;
+        }
+
+        Ok(RotatingFile {
+            path,
+            rotation,
+            file,
+            size: metadata.len(),
+            created: metadata.created().unwrap_or(now).min(now),
+            pending: vec![],
+        })
+    }
+
+    fn needs_rotation(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        let too_large = self.size + len as u64 > self.rotation.max_size;
+        let too_old = !self.rotation.max_age.is_zero()
+            && self.created.elapsed().is_ok_and(|age| age > self.rotation.max_age);
+
+        too_large || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.rotation.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = rotated_path(&self.path, self.rotation.max_files);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+
+            for index in (1..self.rotation.max_files).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.created = SystemTime::now();
+        set_created(&self.file, self.created)?;
+
+        Ok(())
+    }
+
+    /// Writes the buffered records to the file, rotating it first if needed.
+    fn write_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        // A file that can't be rotated is written to anyway, rather than losing the record
+        if self.needs_rotation(self.pending.len()) && let Err(e) = self.rotate() {
+            eprintln!("Failed to rotate {}: {}", self.path.display(), e);
+        }
+
+        let pending = std::mem::take(&mut self.pending);
+        self.file.write_all(&pending)?;
+        self.size += pending.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+
+        if self.pending.ends_with(b"\n") {
+            self.write_pending()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.file.flush()
+    }
+}
+
+/// Records when a log file was created. NTFS gives a file created under a name that was renamed or
+/// deleted moments before the creation time of the old file, which would make a new log look too
+/// old the next time it is opened.
+#[cfg(windows)]
+fn set_created(file: &File, created: SystemTime) -> io::Result<()> {
+    use std::{fs::FileTimes, os::windows::fs::FileTimesExt};
+
+    file.set_times(FileTimes::new().set_created(created))
+}
+
+#[cfg(not(windows))]
+fn set_created(_file: &File, _created: SystemTime) -> io::Result<()> {
+    Ok(())
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+fn format_record(out: FormatCallback, message: &Arguments, record: &Record) {
+    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S.%3f").to_string();
+    let level = record.level();
+
+    out.finish(format_args!(
+        "[{}] [{}]: {}",
+        timestamp, level, message
+    ))
+}
+
+fn format_json_record(out: FormatCallback, message: &Arguments, record: &Record) {
+    out.finish(format_args!("{}", json_record(message, record)))
+}
+
+/// The record as a JSON object. The fields of the record are added next to the standard keys,
+/// which are kept when a field has the same name.
+fn json_record(message: &Arguments, record: &Record) -> Value {
+    let mut fields = Map::new();
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+
+    let mut object = Map::new();
+    object.insert(String::from("timestamp"), json!(Local::now().to_rfc3339_opts(SecondsFormat::Millis, false)));
+    object.insert(String::from("level"), json!(record.level().as_str()));
+    object.insert(String::from("target"), json!(record.target()));
+    object.insert(String::from("message"), json!(message.to_string()));
+
+    for (key, value) in fields {
+        object.entry(key).or_insert(value);
+    }
+
+    Value::Object(object)
+}
+
+/// Collects the fields of a record, keeping numbers, booleans and missing values as they are.
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue(Value::Null);
+        value.visit(&mut json)?;
+
+        self.0.insert(key.to_string(), json.0);
+        Ok(())
+    }
+}
+
+struct JsonValue(Value);
+
+impl<'v> VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: KvValue) -> Result<(), kv::Error> {
+        self.0 = json!(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
 fn set_panic_hook() {
     panic::set_hook(Box::new(|panic_info | {
         let message = match panic_info.payload().downcast_ref::<&str>() {
@@ -47,4 +381,181 @@ fn set_panic_hook() {
         error!("Panic occurred at {}: {}", location, message);
         eprintln!("Panic occurred at {}: {}", location, message);
     }));    
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn json_records_carry_their_fields() {
+        let fields: [(&str, KvValue); 4] = [
+            ("route", KvValue::from_display(&"10.1.0.0/16")),
+            ("interface", KvValue::from(12u32)),
+            ("error_code", KvValue::null()),
+            ("level", KvValue::from("not the level")),
+        ];
+
+        let mut record = json_record(
+            &format_args!("Route 10.1.0.0/16: added"),
+            &Record::builder()
+                .level(Level::Info)
+                .target("route2wsl::wsl_monitor")
+                .key_values(&fields)
+                .args(format_args!("Route 10.1.0.0/16: added"))
+                .build(),
+        );
+
+        assert!(record.as_object_mut().unwrap().remove("timestamp").is_some());
+        assert_eq!(
+            record,
+            json!({
+                "level": "INFO",
+                "target": "route2wsl::wsl_monitor",
+                "message": "Route 10.1.0.0/16: added",
+                "route": "10.1.0.0/16",
+                "interface": 12,
+                "error_code": null,
+            })
+        );
+    }
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("route2wsl-logs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Needs an elevated process, as Administrators are made the owner.
+    #[cfg(windows)]
+    #[test]
+    fn only_created_logs_dirs_and_log_files_are_restricted() {
+        let dir = log_dir("acl");
+        let created = dir.join("logs");
+        fs::write(dir.join(LOG_FILE_NAME), "written by a user\n").unwrap();
+        let existing = security::sddl(&dir).unwrap();
+
+        create_logs_dir(&dir).unwrap();
+        create_logs_dir(&created).unwrap();
+
+        assert_eq!(security::sddl(&dir).unwrap(), existing);
+        assert!(security::sddl(&dir.join(LOG_FILE_NAME)).unwrap().starts_with("O:BAD:P"));
+        assert_eq!(security::sddl(&created).unwrap(), security::ADMIN_ONLY_SDDL);
+        assert!(!security::writable_by_users(&created).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn large_files_are_rotated_and_only_max_files_are_kept() {
+        let dir = log_dir("size");
+        let path = dir.join("route2wsl.log");
+        let rotation = LogRotation {
+            max_size: 10,
+            max_files: 2,
+            max_age: Duration::ZERO,
+        };
+
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 2)).unwrap(), "second\n");
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_logged_through_fern_are_not_split_across_files() {
+        let dir = log_dir("fern");
+        let path = dir.join("route2wsl.log");
+        let rotation = LogRotation {
+            max_size: 200,
+            max_files: 3,
+            max_age: Duration::ZERO,
+        };
+
+        let file: Box<dyn Write + Send> = Box::new(RotatingFile::open(path.clone(), rotation).unwrap());
+        let (_, logger) = Dispatch::new().format(LogFormat::Json.formatter()).chain(file).into_log();
+
+        for index in 0..6 {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("Route 10.{}.0.0/16: added", index))
+                    .level(Level::Info)
+                    .target("route2wsl::routes")
+                    .build(),
+            );
+        }
+
+        let files = [path.clone(), rotated_path(&path, 1), rotated_path(&path, 2)];
+        for file in &files {
+            let contents = fs::read_to_string(file).unwrap();
+            assert!(contents.ends_with('\n'));
+
+            for line in contents.lines() {
+                let record: Value = serde_json::from_str(line).unwrap();
+                assert_eq!(record["level"], "INFO");
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn old_files_are_rotated_and_extra_rotated_files_are_deleted() {
+        let dir = log_dir("age");
+        let path = dir.join("route2wsl.log");
+        fs::write(rotated_path(&path, 2), "old\n").unwrap();
+        let rotation = LogRotation {
+            max_size: 1024,
+            max_files: 1,
+            max_age: Duration::from_secs(60),
+        };
+
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        file.write_all(b"yesterday\n").unwrap();
+        file.created = SystemTime::now() - Duration::from_secs(120);
+        file.write_all(b"today\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "today\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "yesterday\n");
+        assert!(!rotated_path(&path, 2).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_not_rotated_again_when_reopened_after_an_age_rotation() {
+        let dir = log_dir("reopen");
+        let path = dir.join("route2wsl.log");
+        let rotation = LogRotation {
+            max_size: 1024,
+            max_files: 2,
+            max_age: Duration::from_secs(60),
+        };
+
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        file.write_all(b"yesterday\n").unwrap();
+        file.created = SystemTime::now() - Duration::from_secs(120);
+        set_created(&file.file, file.created).unwrap();
+        file.write_all(b"today\n").unwrap();
+        drop(file);
+
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        file.write_all(b"still today\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "today\nstill today\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "yesterday\n");
+        assert!(!rotated_path(&path, 2).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,50 +1,116 @@
+#[cfg(windows)]
 use clap::Parser;
+#[cfg(windows)]
 use cli::{Cli, Commands};
 
+// Other platforms only build the tests of the modules that work without Windows
+#[cfg(any(windows, test))]
 mod cli;
+#[cfg(any(windows, test))]
+mod config;
+#[cfg(any(windows, test))]
+mod control;
+#[cfg(any(windows, test))]
+mod doctor;
+#[cfg(any(windows, test))]
+mod error;
+#[cfg(any(windows, test))]
 mod wsl_monitor;
+#[cfg(any(windows, test))]
+mod wslconfig;
+#[cfg(windows)]
+mod handles;
+#[cfg(any(windows, test))]
 mod hcn;
+#[cfg(any(windows, test))]
+mod interfaces;
+#[cfg(windows)]
 mod hcs;
+#[cfg(windows)]
 mod installer;
+#[cfg(any(windows, test))]
 mod logging;
+#[cfg(windows)]
+mod notifications;
+#[cfg(any(windows, test))]
+mod output;
+#[cfg(windows)]
 mod service;
+#[cfg(any(windows, test))]
 mod routes;
+#[cfg(any(windows, test))]
+mod security;
+#[cfg(any(windows, test))]
+mod state;
+#[cfg(any(windows, test))]
+mod status;
 
+#[cfg(windows)]
 fn main() {
     let cli = Cli::parse();
+    let mut output = output::Output::new(cli.output);
 
-    match cli.command {
-        Commands::Install(cli::RunArgs {
-            wsl_interface,
-            routes,
-            log_level,
-        }) => {
-            if let Err(_e) = installer::install_service(
-                service::SERVICE_NAME,
-                wsl_interface,
-                routes.iter().map(|n| n.to_string()).collect(),
-                log_level
-            ) {
-                println!("{}", _e);
-            }
+    let exit_code = match cli.command {
+        Commands::Install(args) => {
+            let result = installer::install_service(service::SERVICE_NAME, args, &mut output);
+            output.finish(result)
         }
         Commands::Uninstall => {
-            if let Err(_e) = installer::uninstall_service(service::SERVICE_NAME) {
-                println!("{}", _e);
-            }
+            let result = installer::uninstall_service(service::SERVICE_NAME, &mut output);
+            output.finish(result)
         }
         Commands::Inspect => {
-            if let Err(_e) = installer::print_installation_details(service::SERVICE_NAME) {
-                println!("{}", _e);
-            }          
-        }
-        Commands::AddRoute(cli::ChangeRoutesArgs {
-            routes
-        }) => {
-            if let Err(_e) = installer::add_route(service::SERVICE_NAME, routes) {
-                println!("{}", _e);
-            }             
-        }
-        _ => service::bootstrap(),
-    }
+            let result = installer::inspect_installation(service::SERVICE_NAME);
+            output.finish(result)
+        }
+        Commands::Status => {
+            let result = installer::service_status(service::SERVICE_NAME);
+            output.finish(result)
+        }
+        Commands::AddRoute(cli::ChangeRoutesArgs { routes }) => {
+            let result = installer::add_route(service::SERVICE_NAME, routes, &mut output);
+            output.finish(result)
+        }
+        Commands::RemoveRoute(cli::ChangeRoutesArgs { routes }) => {
+            let result = installer::remove_route(service::SERVICE_NAME, routes, &mut output);
+            output.finish(result)
+        }
+        Commands::SetRoutes(cli::ChangeRoutesArgs { routes }) => {
+            let result = installer::set_routes(service::SERVICE_NAME, routes, &mut output);
+            output.finish(result)
+        }
+        Commands::ManagedRoutes => {
+            let result = control::request(&control::Request::ManagedRoutes);
+            output.finish(result)
+        }
+        Commands::Resync => {
+            let result = control::request(&control::Request::Resync);
+            output.finish(result)
+        }
+        Commands::LogLevel(cli::LogLevelArgs { level }) => {
+            let result = control::request(&control::Request::SetLogLevel { level });
+            output.finish(result)
+        }
+        Commands::Doctor(args) => {
+            let config = installer::inspect_installation(service::SERVICE_NAME).ok().map(|d| d.config);
+            let result = doctor::run(&args, config.as_ref());
+            output.finish(result)
+        }
+        Commands::Run(cli::RunCommandArgs { run, foreground: true }) => {
+            let result = service::run_foreground(run);
+            output.finish(result)
+        }
+        Commands::Run(_) => {
+            let result = service::bootstrap();
+            output.finish(result)
+        }
+    };
+
+    std::process::exit(exit_code);
+}
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("route2wsl only runs on Windows");
+    std::process::exit(1);
 }
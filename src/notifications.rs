@@ -0,0 +1,123 @@
+use std::{ffi::c_void, sync::mpsc::Sender};
+
+use windows::Win32::{
+    Foundation::{HANDLE, NO_ERROR, WIN32_ERROR},
+    NetworkManagement::IpHelper::{
+        CancelMibChangeNotify2, MIB_IPFORWARD_ROW2, MIB_IPINTERFACE_ROW, MIB_NOTIFICATION_TYPE,
+        MIB_UNICASTIPADDRESS_ROW, NotifyIpInterfaceChange, NotifyRouteChange2,
+        NotifyUnicastIpAddressChange,
+    },
+    Networking::WinSock::AF_UNSPEC,
+};
+
+use crate::{error::Error, wsl_monitor::MonitorEvent};
+
+/// Subscription to interface, unicast address and route changes, which are forwarded as
+/// `MonitorEvent::NetworkChanged`. The subscription is cancelled when dropped.
+pub struct NetworkNotifications {
+    handles: Vec<HANDLE>,
+    sender: *mut Sender<MonitorEvent>,
+}
+
+impl NetworkNotifications {
+    pub fn register(sender: Sender<MonitorEvent>) -> Result<Self, Error> {
+        let mut notifications = NetworkNotifications {
+            handles: vec![],
+            sender: Box::into_raw(Box::new(sender)),
+        };
+
+        let context = notifications.sender as *const c_void;
+
+        unsafe {
+            let mut handle = HANDLE::default();
+            check(NotifyIpInterfaceChange(
+                AF_UNSPEC,
+                Some(on_interface_change),
+                Some(context),
+                false,
+                &mut handle,
+            ))
+            .map_err(|e| e.context("Failed to subscribe to interface changes"))?;
+            notifications.handles.push(handle);
+
+            let mut handle = HANDLE::default();
+            check(NotifyUnicastIpAddressChange(
+                AF_UNSPEC,
+                Some(on_address_change),
+                Some(context),
+                false,
+                &mut handle,
+            ))
+            .map_err(|e| e.context("Failed to subscribe to address changes"))?;
+            notifications.handles.push(handle);
+
+            let mut handle = HANDLE::default();
+            check(NotifyRouteChange2(
+                AF_UNSPEC,
+                Some(on_route_change),
+                context,
+                false,
+                &mut handle,
+            ))
+            .map_err(|e| e.context("Failed to subscribe to route changes"))?;
+            notifications.handles.push(handle);
+        }
+
+        Ok(notifications)
+    }
+}
+
+impl Drop for NetworkNotifications {
+    fn drop(&mut self) {
+        unsafe {
+            // Cancelling waits for running callbacks, so the sender is no longer in use afterwards
+            for handle in self.handles.drain(..) {
+                let _ = CancelMibChangeNotify2(handle);
+            }
+
+            drop(Box::from_raw(self.sender));
+        }
+    }
+}
+
+fn check(result: WIN32_ERROR) -> Result<(), Error> {
+    if result == NO_ERROR {
+        Ok(())
+    } else {
+        Err(Error::IpHelper {
+            message: windows::core::Error::from(result).message(),
+            code: result.0,
+        })
+    }
+}
+
+unsafe fn notify(context: *const c_void) {
+    unsafe {
+        let sender = &*(context as *const Sender<MonitorEvent>);
+        let _ = sender.send(MonitorEvent::NetworkChanged);
+    }
+}
+
+unsafe extern "system" fn on_interface_change(
+    context: *const c_void,
+    _row: *const MIB_IPINTERFACE_ROW,
+    _notification_type: MIB_NOTIFICATION_TYPE,
+) {
+    unsafe { notify(context) }
+}
+
+unsafe extern "system" fn on_address_change(
+    context: *const c_void,
+    _row: *const MIB_UNICASTIPADDRESS_ROW,
+    _notification_type: MIB_NOTIFICATION_TYPE,
+) {
+    unsafe { notify(context) }
+}
+
+unsafe extern "system" fn on_route_change(
+    context: *const c_void,
+    _row: *const MIB_IPFORWARD_ROW2,
+    _notification_type: MIB_NOTIFICATION_TYPE,
+) {
+    unsafe { notify(context) }
+}
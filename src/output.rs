@@ -0,0 +1,146 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::{error::Error, routes::RouteSpec};
+
+/// How commands print their results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// A single JSON document on standard output
+    Json,
+}
+
+/// The result of a command, printed once the command has finished.
+pub trait Report: Serialize {
+    /// Text printed in text mode. Nothing is printed when it is empty.
+    fn text(&self) -> String {
+        String::new()
+    }
+}
+
+impl Report for () {}
+
+/// The routes configured after a route command.
+impl Report for Vec<RouteSpec> {}
+
+/// The answer of the running service.
+impl Report for Value {
+    fn text(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+/// Prints the progress and result of a command. Text is printed as it comes, while JSON is
+/// collected and printed as one document when the command finishes:
+///
+/// `{"ok": true, "messages": [...], "result": ...}` or
+/// `{"ok": false, "messages": [...], "error": {"kind": ..., "exit_code": ..., "message": ...}}`
+pub struct Output {
+    format: OutputFormat,
+    messages: Vec<String>,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Output {
+            format,
+            messages: vec![],
+        }
+    }
+
+    /// Reports progress of the command.
+    pub fn message(&mut self, message: impl Into<String>) {
+        let message = message.into();
+
+        match self.format {
+            OutputFormat::Text => println!("{}", message),
+            OutputFormat::Json => self.messages.push(message),
+        }
+    }
+
+    /// Prints the result of the command and returns the process exit code.
+    #[cfg(windows)]
+    pub fn finish<T: Report>(self, result: Result<T, Error>) -> i32 {
+        let exit_code = result.as_ref().map_or_else(Error::exit_code, |_| 0);
+
+        match self.format {
+            OutputFormat::Text => match result {
+                Ok(report) => {
+                    let text = report.text();
+
+                    if !text.is_empty() {
+                        println!("{}", text);
+                    }
+                }
+                Err(e) => eprintln!("{}", e.report()),
+            },
+            OutputFormat::Json => println!("{}", self.document(result)),
+        }
+
+        exit_code
+    }
+
+    fn document<T: Report>(self, result: Result<T, Error>) -> Value {
+        match result {
+            Ok(report) => json!({
+                "ok": true,
+                "messages": self.messages,
+                "result": report,
+            }),
+            Err(e) => json!({
+                "ok": false,
+                "messages": self.messages,
+                "error": {
+                    "kind": e.kind(),
+                    "exit_code": e.exit_code(),
+                    "message": e.report(),
+                },
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_document_carries_the_result_and_messages() {
+        let mut output = Output::new(OutputFormat::Json);
+        output.message("Updating service with new routes");
+
+        let routes: Vec<RouteSpec> = vec!["10.96.0.0/12,metric=5".parse().unwrap()];
+
+        assert_eq!(
+            output.document(Ok(routes)),
+            json!({
+                "ok": true,
+                "messages": ["Updating service with new routes"],
+                "result": ["10.96.0.0/12,metric=5"],
+            })
+        );
+    }
+
+    #[test]
+    fn json_document_describes_the_error() {
+        let output = Output::new(OutputFormat::Json);
+        let error = Error::not_found("A valid installation could not be found");
+
+        assert_eq!(
+            output.document::<()>(Err(error)),
+            json!({
+                "ok": false,
+                "messages": [],
+                "error": {
+                    "kind": "not_found",
+                    "exit_code": 9,
+                    "message": "A valid installation could not be found",
+                },
+            })
+        );
+    }
+}
@@ -1,63 +1,796 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
-use ipnetwork::Ipv4Network;
+use ipnetwork::IpNetwork;
 use log::{debug, error};
-use network_interface::NetworkInterface;
-use windows::Win32::{
-    Foundation::NO_ERROR,
-    NetworkManagement::IpHelper::{
-        CreateIpForwardEntry2, InitializeIpForwardEntry, MIB_IPFORWARD_ROW2,
-    },
-    Networking::WinSock::{AF_INET, MIB_IPPROTO_NETMGMT},
-};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, interfaces::HostInterface};
+
+/// A row in the routing table, identified by its destination prefix, interface index and next hop.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Route {
+    pub destination: IpNetwork,
+    pub interface_index: u32,
+    pub next_hop: IpAddr,
+    pub metric: u32,
+}
+
+impl Route {
+    /// Returns true if both routes refer to the same row in the routing table, regardless of metric.
+    pub fn same_row(&self, other: &Route) -> bool {
+        self.destination == other.destination
+            && self.interface_index == other.interface_index
+            && self.next_hop == other.next_hop
+    }
+}
+
+/// A configured route: a destination prefix with an optional metric and next hop that override
+/// the defaults. Written as `10.96.0.0/12,metric=5,via=10.2.0.3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RouteSpec {
+    pub destination: IpNetwork,
+    pub metric: Option<u32>,
+    /// Next hop inside the WSL network, used instead of the address of the WSL interface.
+    pub via: Option<IpAddr>,
+}
+
+impl FromStr for RouteSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let destination = parts.next().unwrap_or_default().trim();
+
+        if !destination.contains('/') {
+            return Err(String::from("Use CIDR format like 10.0.0.0/24 or fd00::/64"));
+        }
+
+        let mut spec = RouteSpec {
+            destination: destination.parse().map_err(|e| format!("{}", e))?,
+            metric: None,
+            via: None,
+        };
+
+        for option in parts {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value but found '{}'", option))?;
+
+            match key.trim() {
+                "metric" => {
+                    let metric = value
+                        .trim()
+                        .parse()
+                        .map_err(|e| format!("Invalid metric '{}': {}", value, e))?;
+                    spec.metric = Some(metric);
+                }
+                "via" => {
+                    let via: IpAddr = value
+                        .trim()
+                        .parse()
+                        .map_err(|e| format!("Invalid next hop '{}': {}", value, e))?;
+
+                    if via.is_ipv4() != spec.destination.is_ipv4() {
+                        return Err(format!(
+                            "Next hop {} is not in the address family of {}",
+                            via, spec.destination
+                        ));
+                    }
+
+                    spec.via = Some(via);
+                }
+                other => return Err(format!("Unknown route option '{}', expected metric or via", other)),
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+impl TryFrom<String> for RouteSpec {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RouteSpec> for String {
+    fn from(value: RouteSpec) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for RouteSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.destination)?;
+
+        if let Some(metric) = self.metric {
+            write!(f, ",metric={}", metric)?;
+        }
+
+        if let Some(via) = self.via {
+            write!(f, ",via={}", via)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Access to the system routing table.
+pub trait RouteTable {
+    /// Lists all rows in the routing table.
+    fn list(&self) -> Result<Vec<Route>, Error>;
+
+    /// Adds a row. Fails if a row with the same destination, interface and next hop exists.
+    fn add(&mut self, route: &Route) -> Result<(), Error>;
+
+    /// Deletes the row with the same destination, interface and next hop. Succeeds if there is no such row.
+    fn delete(&mut self, route: &Route) -> Result<(), Error>;
+
+    /// Updates the metric of the row with the same destination, interface and next hop.
+    fn replace(&mut self, route: &Route) -> Result<(), Error>;
+}
+
+/// The interface and addresses that routes are sent through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gateway {
+    pub interface_index: u32,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl Gateway {
+    /// Uses the first IPv4 address of the interface and its first IPv6 address, preferring
+    /// unique local or global addresses over link-local ones.
+    pub fn from_interface(interface: &HostInterface) -> Result<Gateway, Error> {
+        let ipv4 = interface.addresses.iter().find_map(|addr| match addr.ip {
+            IpAddr::V4(a) => Some(a),
+            _ => None,
+        });
+
+        let ipv6_addresses: Vec<Ipv6Addr> = interface
+            .addresses
+            .iter()
+            .filter_map(|addr| match addr.ip {
+                IpAddr::V6(a) => Some(a),
+                _ => None,
+            })
+            .collect();
+
+        let ipv6 = ipv6_addresses
+            .iter()
+            .find(|a| !a.is_unicast_link_local())
+            .or(ipv6_addresses.first())
+            .copied();
+
+        if ipv4.is_none() && ipv6.is_none() {
+            return Err(Error::not_found(format!("Gateway interface {} has no IP address", interface.name)));
+        }
+
+        Ok(Gateway {
+            interface_index: interface.index,
+            ipv4,
+            ipv6,
+        })
+    }
+
+    /// The next hop for a destination of the same address family, if the gateway has one.
+    pub fn next_hop_for(&self, destination: &IpNetwork) -> Option<IpAddr> {
+        match destination {
+            IpNetwork::V4(_) => self.ipv4.map(IpAddr::V4),
+            IpNetwork::V6(_) => self.ipv6.map(IpAddr::V6),
+        }
+    }
+}
+
+impl Display for Gateway {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let addresses: Vec<String> = self
+            .ipv4
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(self.ipv6.map(IpAddr::V6))
+            .map(|a| a.to_string())
+            .collect();
+
+        write!(f, "{} on interface {}", addresses.join(", "), self.interface_index)
+    }
+}
+
+/// Builds the routing table rows that send the given routes through the gateway, using the
+/// metric unless a route has its own. Routes of an address family the gateway has no address
+/// for are skipped, unless they have their own next hop.
+pub fn routes_for_gateway(gateway: &Gateway, routes: &[RouteSpec], metric: u32) -> Vec<Route> {
+    routes
+        .iter()
+        .filter_map(|route| {
+            let Some(next_hop) = route.via.or_else(|| gateway.next_hop_for(&route.destination)) else {
+                debug!("Gateway IP is incompatible with route {}", route);
+                return None;
+            };
+
+            Some(Route {
+                destination: route.destination,
+                interface_index: gateway.interface_index,
+                next_hop,
+                metric: route.metric.unwrap_or(metric),
+            })
+        })
+        .collect()
+}
+
+/// Outcome of reconciling a desired route with the routing table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteStatus {
+    /// The route was already in the routing table.
+    Present,
+    /// The route was missing and has been added.
+    Added,
+    /// The route was in the routing table with a different metric, which has been corrected.
+    MetricUpdated { previous: u32 },
+    /// A row that was not added by route2wsl has a different metric. It is left as it is.
+    NotManaged { metric: u32 },
+    /// The route could not be added or updated.
+    Failed(String),
+}
+
+impl Display for RouteStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteStatus::Present => write!(f, "present"),
+            RouteStatus::Added => write!(f, "added"),
+            RouteStatus::MetricUpdated { previous } => write!(f, "metric updated from {}", previous),
+            RouteStatus::NotManaged { metric } => write!(f, "left with metric {}, it was not added by route2wsl", metric),
+            RouteStatus::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// Brings the routing table in line with the desired routes. Managed routes that are no longer
+/// desired, such as routes through a previous gateway, are deleted, missing routes are added and
+/// managed routes with the wrong metric are updated. Rows added by someone else are never changed.
+/// `managed` is updated to the set of rows that this process owns afterwards.
+pub fn reconcile_routes(
+    route_table: &mut impl RouteTable,
+    managed: &mut Vec<Route>,
+    desired: &[Route],
+) -> Result<Vec<(Route, RouteStatus)>, Error> {
+    let actual = route_table.list()?;
+
+    // Rows that are gone from the table, for example because their interface was removed, need no cleanup
+    managed.retain(|route| {
+        desired.iter().any(|d| d.same_row(route)) || actual.iter().any(|a| a.same_row(route))
+    });
+    remove_stale_routes(route_table, managed, desired);
+
+    let mut statuses = Vec::with_capacity(desired.len());
+
+    for route in desired {
+        let status = match actual.iter().find(|a| a.same_row(route)) {
+            Some(existing) if existing.metric == route.metric => RouteStatus::Present,
+            Some(existing) if !managed.iter().any(|m| m.same_row(route)) => RouteStatus::NotManaged {
+                metric: existing.metric,
+            },
+            Some(existing) => {
+                debug!(
+                    route:% = route.destination, gateway:% = route.next_hop, interface = route.interface_index, metric = route.metric;
+                    "Updating metric of route {} via gateway {} from {} to {}",
+                    route.destination, route.next_hop, existing.metric, route.metric
+                );
+
+                match route_table.replace(route) {
+                    Ok(()) => RouteStatus::MetricUpdated {
+                        previous: existing.metric,
+                    },
+                    Err(e) => failed(route, e),
+                }
+            }
+            None => {
+                debug!(
+                    route:% = route.destination, gateway:% = route.next_hop, interface = route.interface_index, metric = route.metric;
+                    "Setting route {} via gateway {}",
+                    route.destination, route.next_hop
+                );
+
+                match route_table.add(route) {
+                    Ok(()) => RouteStatus::Added,
+                    Err(e) => failed(route, e),
+                }
+            }
+        };
+
+        // A route that was already present is only owned if this process added it
+        if status == RouteStatus::Added {
+            managed.retain(|m| !m.same_row(route));
+            managed.push(route.clone());
+        }
+
+        statuses.push((route.clone(), status));
+    }
+
+    Ok(statuses)
+}
+
+/// The status of a route that could not be added or updated, logged with the error code.
+fn failed(route: &Route, e: Error) -> RouteStatus {
+    error!(
+        route:% = route.destination, gateway:% = route.next_hop, interface = route.interface_index, error_code = e.code();
+        "Failed to set route {} via gateway {}: {}", route.destination, route.next_hop, e
+    );
+
+    RouteStatus::Failed(e.to_string())
+}
+
+/// Deletes all managed routes. Routes that could not be deleted are kept in `managed` and the
+/// last failure is returned.
+pub fn remove_routes(route_table: &mut impl RouteTable, managed: &mut Vec<Route>) -> Result<(), Error> {
+    match remove_stale_routes(route_table, managed, &[]) {
+        Some(e) => Err(e.context(format!("Failed to remove {} route(s)", managed.len()))),
+        None => Ok(()),
+    }
+}
+
+/// Deletes managed routes that are not desired. Returns the last failure, if any.
+fn remove_stale_routes(
+    route_table: &mut impl RouteTable,
+    managed: &mut Vec<Route>,
+    desired: &[Route],
+) -> Option<Error> {
+    let mut failure = None;
+
+    managed.retain(|route| {
+        if desired.iter().any(|d| d.same_row(route)) {
+            return true;
+        }
+
+        debug!(
+            route:% = route.destination, gateway:% = route.next_hop, interface = route.interface_index;
+            "Removing route {} via gateway {}",
+            route.destination, route.next_hop
+        );
+
+        match route_table.delete(route) {
+            Ok(()) => false,
+            Err(e) => {
+                error!(
+                    route:% = route.destination, gateway:% = route.next_hop, interface = route.interface_index, error_code = e.code();
+                    "Failed to remove route {}: {}", route.destination, e
+                );
+                failure = Some(e);
+                true
+            }
+        }
+    });
+
+    failure
+}
+
+#[cfg(windows)]
+pub use ip_helper::IpHelperRouteTable;
 
-pub fn add_routes(gateway: NetworkInterface, routes: Vec<Ipv4Network>) {
-    unsafe {
-        let mut gateway_address: Option<Ipv4Addr> = None;
+#[cfg(windows)]
+mod ip_helper {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-        for addr in gateway.addr.clone() {
-            match addr.ip() {
-                IpAddr::V4(a) => {
-                    gateway_address = Some(a);
-                    break;
+    use ipnetwork::IpNetwork;
+    use windows::Win32::{
+        Foundation::{ERROR_NOT_FOUND, NO_ERROR, WIN32_ERROR},
+        NetworkManagement::IpHelper::{
+            CreateIpForwardEntry2, DeleteIpForwardEntry2, FreeMibTable, GetIpForwardTable2,
+            InitializeIpForwardEntry, MIB_IPFORWARD_ROW2, MIB_IPFORWARD_TABLE2, SetIpForwardEntry2,
+        },
+        Networking::WinSock::{AF_INET, AF_INET6, AF_UNSPEC, MIB_IPPROTO_NETMGMT, SOCKADDR_INET},
+    };
+
+    use super::{Route, RouteTable};
+    use crate::error::Error;
+
+    /// Routing table backed by the IP Helper API.
+    pub struct IpHelperRouteTable;
+
+    impl RouteTable for IpHelperRouteTable {
+        fn list(&self) -> Result<Vec<Route>, Error> {
+            unsafe {
+                let mut table: *mut MIB_IPFORWARD_TABLE2 = std::ptr::null_mut();
+                check(GetIpForwardTable2(AF_UNSPEC, &mut table))?;
+
+                let rows = std::slice::from_raw_parts(
+                    (*table).Table.as_ptr(),
+                    (*table).NumEntries as usize,
+                );
+
+                let routes = rows.iter().filter_map(|row| from_row(row)).collect();
+
+                FreeMibTable(table as *const core::ffi::c_void);
+
+                Ok(routes)
+            }
+        }
+
+        fn add(&mut self, route: &Route) -> Result<(), Error> {
+            unsafe { check(CreateIpForwardEntry2(&to_row(route))) }
+        }
+
+        fn delete(&mut self, route: &Route) -> Result<(), Error> {
+            unsafe {
+                match DeleteIpForwardEntry2(&to_row(route)) {
+                    ERROR_NOT_FOUND => Ok(()),
+                    result => check(result),
                 }
-                _ => {}
             }
         }
 
-        if gateway_address == None {
-            debug!("Gateway IP is incompatible {}", gateway.addr[0].ip());
-            return;
+        fn replace(&mut self, route: &Route) -> Result<(), Error> {
+            unsafe { check(SetIpForwardEntry2(&to_row(route))) }
         }
+    }
 
-        for route in routes {
-            debug!(
-                "Setting route {} via gateway {}",
-                route.ip(),
-                gateway_address.unwrap()
-            );
+    fn check(result: WIN32_ERROR) -> Result<(), Error> {
+        if result == NO_ERROR {
+            Ok(())
+        } else {
+            Err(Error::IpHelper {
+                message: windows::core::Error::from(result).message(),
+                code: result.0,
+            })
+        }
+    }
 
+    unsafe fn to_row(route: &Route) -> MIB_IPFORWARD_ROW2 {
+        unsafe {
             let mut row: MIB_IPFORWARD_ROW2 = MIB_IPFORWARD_ROW2::default();
             InitializeIpForwardEntry(&mut row);
 
-            row.InterfaceIndex = gateway.index;
-            row.DestinationPrefix.PrefixLength = route.prefix();
-            row.DestinationPrefix.Prefix.si_family = AF_INET;
-            row.DestinationPrefix.Prefix.Ipv4.sin_addr.S_un.S_addr =
-                u32::from_ne_bytes(route.ip().octets());
-
-            row.NextHop.si_family = AF_INET;
-            row.NextHop.Ipv4.sin_addr.S_un.S_addr =
-                u32::from_ne_bytes(gateway_address.unwrap().octets());
-            row.Metric = 1;
+            row.InterfaceIndex = route.interface_index;
+            row.DestinationPrefix.PrefixLength = route.destination.prefix();
+            row.DestinationPrefix.Prefix = to_sockaddr(route.destination.ip(), 0);
+            row.NextHop = to_sockaddr(route.next_hop, route.interface_index);
+            row.Metric = route.metric;
             row.Protocol = MIB_IPPROTO_NETMGMT;
 
-            let result = CreateIpForwardEntry2(&row);
+            row
+        }
+    }
+
+    unsafe fn from_row(row: &MIB_IPFORWARD_ROW2) -> Option<Route> {
+        unsafe {
+            let destination_ip = from_sockaddr(&row.DestinationPrefix.Prefix)?;
+            let destination =
+                IpNetwork::new(destination_ip, row.DestinationPrefix.PrefixLength).ok()?;
+
+            Some(Route {
+                destination,
+                interface_index: row.InterfaceIndex,
+                next_hop: from_sockaddr(&row.NextHop)?,
+                metric: row.Metric,
+            })
+        }
+    }
+
+    fn to_sockaddr(ip: IpAddr, interface_index: u32) -> SOCKADDR_INET {
+        let mut addr = SOCKADDR_INET::default();
+
+        match ip {
+            IpAddr::V4(ip) => {
+                addr.Ipv4.sin_family = AF_INET;
+                addr.Ipv4.sin_addr.S_un.S_addr = u32::from_ne_bytes(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                addr.Ipv6.sin6_family = AF_INET6;
+                addr.Ipv6.sin6_addr.u.Byte = ip.octets();
+
+                // Link-local next hops are only meaningful together with their interface
+                if ip.is_unicast_link_local() {
+                    addr.Ipv6.Anonymous.sin6_scope_id = interface_index;
+                }
+            }
+        }
+
+        addr
+    }
+
+    unsafe fn from_sockaddr(addr: &SOCKADDR_INET) -> Option<IpAddr> {
+        unsafe {
+            match addr.si_family {
+                AF_INET => Some(IpAddr::V4(Ipv4Addr::from(
+                    addr.Ipv4.sin_addr.S_un.S_addr.to_ne_bytes(),
+                ))),
+                AF_INET6 => Some(IpAddr::V6(Ipv6Addr::from(addr.Ipv6.sin6_addr.u.Byte))),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// In-memory routing table used in tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryRouteTable {
+    pub routes: Vec<Route>,
+}
+
+#[cfg(test)]
+impl RouteTable for MemoryRouteTable {
+    fn list(&self) -> Result<Vec<Route>, Error> {
+        Ok(self.routes.clone())
+    }
+
+    fn add(&mut self, route: &Route) -> Result<(), Error> {
+        if self.routes.iter().any(|r| r.same_row(route)) {
+            return Err(Error::IpHelper {
+                message: String::from("The object already exists"),
+                code: 5010,
+            });
+        }
+
+        self.routes.push(route.clone());
+        Ok(())
+    }
+
+    fn delete(&mut self, route: &Route) -> Result<(), Error> {
+        self.routes.retain(|r| !r.same_row(route));
+        Ok(())
+    }
 
-            if result != NO_ERROR {
-                let error = windows::core::Error::from(result);
-                error!("Failed to set route {}: {} {}", route.ip(), result.0, error);
+    fn replace(&mut self, route: &Route) -> Result<(), Error> {
+        match self.routes.iter_mut().find(|r| r.same_row(route)) {
+            Some(existing) => {
+                *existing = route.clone();
+                Ok(())
             }
+            None => Err(Error::IpHelper {
+                message: String::from("Element not found"),
+                code: 1168,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaces::InterfaceAddress;
+
+    fn interface(index: u32, addresses: &[&str]) -> HostInterface {
+        HostInterface {
+            name: String::from("vEthernet (WSL)"),
+            index,
+            addresses: addresses
+                .iter()
+                .map(|a| InterfaceAddress {
+                    ip: a.parse().unwrap(),
+                    netmask: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn route(destination: &str, interface_index: u32, next_hop: [u8; 4]) -> Route {
+        Route {
+            destination: destination.parse().unwrap(),
+            interface_index,
+            next_hop: IpAddr::from(next_hop),
+            metric: 1,
         }
     }
+
+    #[test]
+    fn routes_for_gateway_creates_a_row_per_route() {
+        let gateway = Gateway::from_interface(&interface(12, &["172.20.0.1"])).unwrap();
+
+        let routes = routes_for_gateway(
+            &gateway,
+            &["10.152.183.0/24".parse().unwrap(), "10.1.0.0/16".parse().unwrap()],
+            1,
+        );
+
+        assert_eq!(
+            routes,
+            vec![
+                route("10.152.183.0/24", 12, [172, 20, 0, 1]),
+                route("10.1.0.0/16", 12, [172, 20, 0, 1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_for_gateway_uses_a_next_hop_of_the_same_family() {
+        let gateway = Gateway::from_interface(&interface(
+            12,
+            &["fe80::1", "172.20.0.1", "fd00:20::1"],
+        ))
+        .unwrap();
+
+        let routes = routes_for_gateway(
+            &gateway,
+            &["10.96.0.0/12".parse().unwrap(), "fd00:10:96::/112".parse().unwrap()],
+            1,
+        );
+
+        assert_eq!(routes[0].next_hop, "172.20.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(routes[1].next_hop, "fd00:20::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn routes_for_gateway_skips_routes_without_a_matching_family() {
+        let gateway = Gateway::from_interface(&interface(12, &["fe80::1"])).unwrap();
+
+        let routes = routes_for_gateway(
+            &gateway,
+            &["10.96.0.0/12".parse().unwrap(), "fd00:10:96::/112".parse().unwrap()],
+            1,
+        );
+
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].next_hop, "fe80::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn routes_for_gateway_applies_route_options() {
+        let gateway = Gateway::from_interface(&interface(12, &["172.20.0.1"])).unwrap();
+
+        let routes = routes_for_gateway(
+            &gateway,
+            &["10.2.0.0/16,via=172.20.0.3,metric=5".parse().unwrap()],
+            1,
+        );
+
+        assert_eq!(
+            routes,
+            vec![Route {
+                metric: 5,
+                ..route("10.2.0.0/16", 12, [172, 20, 0, 3])
+            }]
+        );
+    }
+
+    #[test]
+    fn route_spec_round_trips_through_a_string() {
+        let spec: RouteSpec = "10.96.0.0/12, metric=5, via=10.2.0.3".parse().unwrap();
+
+        assert_eq!(
+            spec,
+            RouteSpec {
+                destination: "10.96.0.0/12".parse().unwrap(),
+                metric: Some(5),
+                via: Some("10.2.0.3".parse().unwrap()),
+            }
+        );
+        assert_eq!(spec.to_string(), "10.96.0.0/12,metric=5,via=10.2.0.3");
+        assert_eq!(spec.to_string().parse::<RouteSpec>().unwrap(), spec);
+    }
+
+    #[test]
+    fn route_spec_rejects_invalid_options() {
+        assert!("10.96.0.0".parse::<RouteSpec>().is_err());
+        assert!("10.96.0.0/12,metric=high".parse::<RouteSpec>().is_err());
+        assert!("10.96.0.0/12,via=fd00::1".parse::<RouteSpec>().is_err());
+        assert!("10.96.0.0/12,mtu=1400".parse::<RouteSpec>().is_err());
+    }
+
+    #[test]
+    fn reconcile_removes_only_stale_managed_rows() {
+        let user_route = route("10.1.0.0/16", 4, [192, 168, 1, 1]);
+        let old_route = route("10.1.0.0/16", 12, [172, 20, 0, 1]);
+        let new_route = route("10.1.0.0/16", 15, [172, 28, 0, 1]);
+
+        let mut table = MemoryRouteTable {
+            routes: vec![user_route.clone(), old_route.clone()],
+        };
+        let mut managed = vec![old_route];
+
+        let statuses =
+            reconcile_routes(&mut table, &mut managed, std::slice::from_ref(&new_route)).unwrap();
+
+        assert_eq!(statuses, vec![(new_route.clone(), RouteStatus::Added)]);
+        assert_eq!(table.routes, vec![user_route, new_route.clone()]);
+        assert_eq!(managed, vec![new_route]);
+    }
+
+    #[test]
+    fn reconcile_repairs_missing_and_wrong_metric_rows() {
+        let missing = route("10.1.0.0/16", 12, [172, 20, 0, 1]);
+        let wrong_metric = route("10.152.183.0/24", 12, [172, 20, 0, 1]);
+        let in_sync = route("10.96.0.0/12", 12, [172, 20, 0, 1]);
+
+        let mut table = MemoryRouteTable {
+            routes: vec![
+                Route {
+                    metric: 25,
+                    ..wrong_metric.clone()
+                },
+                in_sync.clone(),
+            ],
+        };
+        let mut managed = vec![missing.clone(), wrong_metric.clone(), in_sync.clone()];
+        let desired = managed.clone();
+
+        let statuses = reconcile_routes(&mut table, &mut managed, &desired).unwrap();
+
+        assert_eq!(
+            statuses,
+            vec![
+                (missing.clone(), RouteStatus::Added),
+                (
+                    wrong_metric.clone(),
+                    RouteStatus::MetricUpdated { previous: 25 }
+                ),
+                (in_sync.clone(), RouteStatus::Present),
+            ]
+        );
+        assert_eq!(table.routes.len(), 3);
+        assert!(desired.iter().all(|d| table.routes.contains(d)));
+        assert_eq!(managed.len(), 3);
+    }
+
+    #[test]
+    fn reconcile_does_not_take_ownership_of_existing_rows() {
+        let existing = route("10.1.0.0/16", 12, [172, 20, 0, 1]);
+        let mut table = MemoryRouteTable {
+            routes: vec![existing.clone()],
+        };
+        let mut managed = vec![];
+
+        reconcile_routes(&mut table, &mut managed, std::slice::from_ref(&existing)).unwrap();
+
+        assert!(managed.is_empty());
+    }
+
+    #[test]
+    fn routes_with_another_metric_that_were_not_added_survive_remove_routes() {
+        let desired = route("10.1.0.0/16", 12, [172, 20, 0, 1]);
+        let user_route = Route {
+            metric: 25,
+            ..desired.clone()
+        };
+        let mut table = MemoryRouteTable {
+            routes: vec![user_route.clone()],
+        };
+        let mut managed = vec![];
+
+        let statuses = reconcile_routes(&mut table, &mut managed, std::slice::from_ref(&desired)).unwrap();
+        remove_routes(&mut table, &mut managed).unwrap();
+
+        assert_eq!(statuses, vec![(desired, RouteStatus::NotManaged { metric: 25 })]);
+        assert_eq!(table.routes, vec![user_route]);
+    }
+
+    #[test]
+    fn memory_table_rejects_duplicate_rows() {
+        let mut table = MemoryRouteTable::default();
+        let route = route("10.1.0.0/16", 3, [172, 20, 0, 1]);
+
+        assert!(table.add(&route).is_ok());
+        assert!(table.add(&Route { metric: 5, ..route.clone() }).is_err());
+
+        assert!(table.replace(&Route { metric: 5, ..route.clone() }).is_ok());
+        assert_eq!(table.routes[0].metric, 5);
+
+        assert!(table.delete(&route).is_ok());
+        assert!(table.routes.is_empty());
+    }
+
+    #[test]
+    fn remove_routes_leaves_user_routes_in_place() {
+        let user_route = route("10.1.0.0/16", 4, [192, 168, 1, 1]);
+        let managed_route = route("10.152.183.0/24", 12, [172, 20, 0, 1]);
+        let gone_route = route("10.96.0.0/12", 9, [172, 18, 0, 1]);
+
+        let mut table = MemoryRouteTable {
+            routes: vec![user_route.clone(), managed_route.clone()],
+        };
+        let mut managed = vec![managed_route, gone_route];
+
+        assert!(remove_routes(&mut table, &mut managed).is_ok());
+
+        assert_eq!(table.routes, vec![user_route]);
+        assert!(managed.is_empty());
+    }
 }
@@ -0,0 +1,323 @@
+use std::{fs, io, path::Path};
+
+/// Owned by Administrators, with full control for SYSTEM and Administrators and read access for
+/// users. The DACL is protected, so nothing is inherited from the parent directory.
+#[cfg(windows)]
+pub const ADMIN_ONLY_SDDL: &str = "O:BAD:PAI(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)(A;OICI;0x1200a9;;;BU)";
+
+/// Creates the directory with [`ADMIN_ONLY_SDDL`] and its missing parents with the permissions
+/// they inherit. A directory that already exists is left as it is; use [`restrict`] on the ones
+/// route2wsl owns.
+#[cfg(windows)]
+pub fn create_dir_all(dir: &Path) -> io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+
+    if let Some(parent) = dir.parent() && !parent.as_os_str().is_empty() {
+        fs::create_dir_all(parent)?;
+    }
+
+    win::create_dir(dir, ADMIN_ONLY_SDDL)
+}
+
+#[cfg(not(windows))]
+pub fn create_dir_all(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+/// Whether a directory can be changed by someone other than SYSTEM and Administrators, either
+/// through its DACL or because they own it.
+#[cfg(windows)]
+pub fn writable_by_users(dir: &Path) -> io::Result<bool> {
+    win::writable_by_users(dir)
+}
+
+/// Makes Administrators the owner of an existing file or directory and replaces its DACL with the
+/// one of [`ADMIN_ONLY_SDDL`], so a file or directory a user created in advance can't be used to
+/// tamper with what the service reads or writes.
+#[cfg(windows)]
+pub fn restrict(path: &Path) -> io::Result<()> {
+    win::set_security(path, ADMIN_ONLY_SDDL)
+}
+
+#[cfg(not(windows))]
+pub fn restrict(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Writes a file that only SYSTEM and Administrators can change. The contents go to a new,
+/// restricted file that replaces the old one, so handles a user opened on the old file can't be
+/// used to change the new contents.
+pub fn write_restricted(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+
+    let result = (|| {
+        fs::File::create(&temp)?;
+        restrict(Path::new(&temp))?;
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    result
+}
+
+#[cfg(windows)]
+pub use win::SecurityDescriptor;
+#[cfg(all(windows, test))]
+pub use win::sddl;
+
+#[cfg(windows)]
+mod win {
+    use std::{io, path::Path};
+
+    use windows::{
+        Win32::{
+            Foundation::{ERROR_ALREADY_EXISTS, GENERIC_ALL, GENERIC_WRITE, HLOCAL, LocalFree},
+            Security::{
+                ACCESS_ALLOWED_ACE, ACL,
+                Authorization::{
+                    ConvertStringSecurityDescriptorToSecurityDescriptorW, GetNamedSecurityInfoW, SDDL_REVISION_1,
+                    SE_FILE_OBJECT, SetNamedSecurityInfoW,
+                },
+                DACL_SECURITY_INFORMATION, GetAce, GetSecurityDescriptorDacl, GetSecurityDescriptorOwner,
+                INHERIT_ONLY_ACE, IsWellKnownSid, OWNER_SECURITY_INFORMATION, PROTECTED_DACL_SECURITY_INFORMATION,
+                PSECURITY_DESCRIPTOR, PSID, SECURITY_ATTRIBUTES, WinBuiltinAdministratorsSid, WinLocalSystemSid,
+            },
+            Storage::FileSystem::{
+                CreateDirectoryW, DELETE, FILE_ADD_FILE, FILE_ADD_SUBDIRECTORY, FILE_DELETE_CHILD, WRITE_DAC,
+                WRITE_OWNER,
+            },
+            System::SystemServices::ACCESS_ALLOWED_ACE_TYPE,
+        },
+        core::{BOOL, HSTRING},
+    };
+
+    /// A security descriptor parsed from an SDDL string, freed when dropped.
+    pub struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+    impl SecurityDescriptor {
+        pub fn from_sddl(sddl: &str) -> io::Result<Self> {
+            let mut descriptor = PSECURITY_DESCRIPTOR::default();
+
+            unsafe {
+                ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                    &HSTRING::from(sddl),
+                    SDDL_REVISION_1,
+                    &mut descriptor,
+                    None,
+                )?;
+            }
+
+            Ok(SecurityDescriptor(descriptor))
+        }
+
+        /// Attributes that create an object with this descriptor. They borrow the descriptor.
+        pub fn attributes(&self) -> SECURITY_ATTRIBUTES {
+            SECURITY_ATTRIBUTES {
+                nLength: size_of::<SECURITY_ATTRIBUTES>() as u32,
+                lpSecurityDescriptor: self.0.0,
+                bInheritHandle: BOOL::from(false),
+            }
+        }
+
+        fn owner(&self) -> io::Result<PSID> {
+            let mut owner = PSID::default();
+            let mut defaulted = BOOL::default();
+
+            unsafe { GetSecurityDescriptorOwner(self.0, &mut owner, &mut defaulted)? };
+            Ok(owner)
+        }
+
+        fn dacl(&self) -> io::Result<*mut ACL> {
+            let mut present = BOOL::default();
+            let mut dacl = std::ptr::null_mut();
+            let mut defaulted = BOOL::default();
+
+            unsafe { GetSecurityDescriptorDacl(self.0, &mut present, &mut dacl, &mut defaulted)? };
+            Ok(dacl)
+        }
+    }
+
+    impl Drop for SecurityDescriptor {
+        fn drop(&mut self) {
+            unsafe {
+                LocalFree(Some(HLOCAL(self.0.0)));
+            }
+        }
+    }
+
+    pub fn create_dir(dir: &Path, sddl: &str) -> io::Result<()> {
+        let descriptor = SecurityDescriptor::from_sddl(sddl)?;
+        let attributes = descriptor.attributes();
+
+        match unsafe { CreateDirectoryW(&HSTRING::from(dir.as_os_str()), Some(&attributes)) } {
+            Ok(()) => Ok(()),
+            // Created by someone else in the meantime, so its security can't be trusted
+            Err(e) if e.code() == ERROR_ALREADY_EXISTS.to_hresult() => set_security(dir, sddl),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Rights that let a user add, replace or delete what is in a directory, or change who may.
+    const WRITE_ACCESS: u32 = FILE_ADD_FILE.0
+        | FILE_ADD_SUBDIRECTORY.0
+        | FILE_DELETE_CHILD.0
+        | DELETE.0
+        | WRITE_DAC.0
+        | WRITE_OWNER.0
+        | GENERIC_WRITE.0
+        | GENERIC_ALL.0;
+
+    pub fn writable_by_users(dir: &Path) -> io::Result<bool> {
+        let mut owner = PSID::default();
+        let mut dacl: *mut ACL = std::ptr::null_mut();
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+
+        unsafe {
+            GetNamedSecurityInfoW(
+                &HSTRING::from(dir.as_os_str()),
+                SE_FILE_OBJECT,
+                OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION,
+                Some(&mut owner),
+                None,
+                Some(&mut dacl),
+                None,
+                &mut descriptor,
+            )
+            .ok()?;
+        }
+
+        // Freed on return, the owner and DACL point into it
+        let _descriptor = SecurityDescriptor(descriptor);
+        let is_admin = |sid: PSID| unsafe {
+            IsWellKnownSid(sid, WinLocalSystemSid).as_bool() || IsWellKnownSid(sid, WinBuiltinAdministratorsSid).as_bool()
+        };
+
+        // Without a DACL everyone has full control
+        if !is_admin(owner) || dacl.is_null() {
+            return Ok(true);
+        }
+
+        for index in 0..unsafe { (*dacl).AceCount } {
+            let mut ace = std::ptr::null_mut();
+            unsafe { GetAce(dacl, index.into(), &mut ace)? };
+            let ace = unsafe { &*(ace as *const ACCESS_ALLOWED_ACE) };
+
+            let applies = ace.Header.AceType as u32 == ACCESS_ALLOWED_ACE_TYPE
+                && ace.Header.AceFlags as u32 & INHERIT_ONLY_ACE.0 == 0;
+            let sid = PSID(&ace.SidStart as *const u32 as *mut _);
+
+            if applies && ace.Mask & WRITE_ACCESS != 0 && !is_admin(sid) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub fn set_security(path: &Path, sddl: &str) -> io::Result<()> {
+        let descriptor = SecurityDescriptor::from_sddl(sddl)?;
+
+        let result = unsafe {
+            SetNamedSecurityInfoW(
+                &HSTRING::from(path.as_os_str()),
+                SE_FILE_OBJECT,
+                OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+                Some(descriptor.owner()?),
+                None,
+                Some(descriptor.dacl()?),
+                None,
+            )
+        };
+
+        result.ok().map_err(io::Error::from)
+    }
+
+    /// The owner and DACL of a file or directory as an SDDL string.
+    #[cfg(test)]
+    pub fn sddl(path: &Path) -> io::Result<String> {
+        use windows::{
+            Win32::Security::Authorization::{
+                ConvertSecurityDescriptorToStringSecurityDescriptorW, GetNamedSecurityInfoW,
+            },
+            core::PWSTR,
+        };
+
+        let information = OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION;
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+
+        unsafe {
+            GetNamedSecurityInfoW(
+                &HSTRING::from(path.as_os_str()),
+                SE_FILE_OBJECT,
+                information,
+                None,
+                None,
+                None,
+                None,
+                &mut descriptor,
+            )
+            .ok()?;
+        }
+
+        let descriptor = SecurityDescriptor(descriptor);
+        let mut sddl = PWSTR::null();
+
+        unsafe {
+            ConvertSecurityDescriptorToStringSecurityDescriptorW(descriptor.0, SDDL_REVISION_1, information, &mut sddl, None)?;
+        }
+
+        let result = unsafe { sddl.to_string() }.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+
+        unsafe {
+            LocalFree(Some(HLOCAL(sddl.0 as _)));
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restricted_files_are_replaced() {
+        let dir = std::env::temp_dir().join(format!("route2wsl-security-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        fs::write(&path, "written by a user").unwrap();
+
+        write_restricted(&path, "written by the installer").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "written by the installer");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Needs an elevated process, as Administrators are made the owner.
+    #[cfg(windows)]
+    #[test]
+    fn directories_get_the_admin_only_descriptor() {
+        let root = std::env::temp_dir().join(format!("route2wsl-acl-{}", std::process::id()));
+        let existing = root.join("existing");
+        fs::create_dir_all(&existing).unwrap();
+
+        create_dir_all(&root.join("route2wsl").join("logs")).unwrap();
+        restrict(&existing).unwrap();
+
+        for dir in [root.join("route2wsl").join("logs"), existing] {
+            assert_eq!(sddl(&dir).unwrap(), ADMIN_ONLY_SDDL);
+        }
+        assert_ne!(sddl(&root.join("route2wsl")).unwrap(), ADMIN_ONLY_SDDL);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
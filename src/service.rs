@@ -1,9 +1,16 @@
-use std::sync::mpsc;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex, mpsc,
+        OnceLock, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
 
 use clap::Parser;
-use cli::{Cli, Commands};
-use ipnetwork::Ipv4Network;
 use log::{LevelFilter, error, info};
+use windows::{Win32::System::Console::SetConsoleCtrlHandler, core::BOOL};
 use windows_service::{
     define_windows_service,
     service::{
@@ -13,71 +20,189 @@ use windows_service::{
     service_control_handler::{self, ServiceControlHandlerResult},
 };
 
-use crate::{cli, wsl_monitor::WslMonitor, logging::init_service_logger};
+use crate::{
+    cli::{Cli, Commands, RunArgs, RunCommandArgs},
+    config::{Config, default_log_dir},
+    control::{self, ControlHandler, PIPE_NAME, PipeListener},
+    doctor,
+    error::Error,
+    logging::{LogFormat, LogRotation, init_console_logger, init_service_logger},
+    notifications::NetworkNotifications,
+    routes::IpHelperRouteTable,
+    state::{foreground_routes_path, managed_routes_path},
+    wsl_monitor::{DetectionOptions, MonitorEvent, WslGatewayDetector, WslMonitor},
+    wslconfig,
+};
 
 pub const SERVICE_NAME: &str = "RouteToWSL";
 
-pub fn bootstrap() {
-    windows_service::service_dispatcher::start(SERVICE_NAME, ffi_service_main).unwrap();
+pub fn bootstrap() -> Result<(), Error> {
+    windows_service::service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(|e| {
+        Error::scm("Failed to start as a service, use run --foreground to run in a console", e)
+    })
 }
 
-define_windows_service!(ffi_service_main, service_main);
+/// Runs the monitor in the console until Ctrl+C is pressed, logging to stderr.
+pub fn run_foreground(args: RunArgs) -> Result<(), Error> {
+    let config = args.to_config()?;
 
-fn service_main(_arguments: Vec<std::ffi::OsString>) {
-    let cli: Cli;
+    init_console_logger(config.log_level, config.log_format)
+        .map_err(|e| Error::io("Failed to initialize logging", e))?;
 
-    match Cli::try_parse() {
-        Ok(a) => cli = a,
-        Err(e) => {
-            eprintln!("Commandline parsing failed: {}", e);
-            if let Err(e) = init_service_logger(LevelFilter::Info) {
-                eprintln!("Failed to initialize logging: {}", e);
-            } else {
-                error!("Commandline parsing failed: {}", e)
+    let (event_sender, event_receiver) = mpsc::channel();
+    stop_on_ctrl_c(event_sender.clone())?;
+
+    info!("Running in the foreground, press Ctrl+C to stop");
+
+    let keep_routes_on_stop = AtomicBool::new(config.keep_routes_on_stop);
+    let result = run_monitor(config, foreground_routes_path, event_sender, event_receiver, &keep_routes_on_stop);
+    console_stopped();
+    result?;
+
+    info!("Stopped");
+    Ok(())
+}
+
+/// Receives the stop event of Ctrl+C, Ctrl+Break and closing the console.
+static CONSOLE_EVENTS: OnceLock<mpsc::Sender<MonitorEvent>> = OnceLock::new();
+
+/// Set once the monitor has stopped and removed its routes.
+static CONSOLE_STOPPED: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+fn stop_on_ctrl_c(events: mpsc::Sender<MonitorEvent>) -> Result<(), Error> {
+    let _ = CONSOLE_EVENTS.set(events);
+
+    unsafe { SetConsoleCtrlHandler(Some(console_ctrl_handler), true) }
+        .map_err(|e| Error::io("Failed to handle Ctrl+C", e))
+}
+
+unsafe extern "system" fn console_ctrl_handler(_ctrl_type: u32) -> BOOL {
+    match CONSOLE_EVENTS.get() {
+        Some(events) => {
+            let _ = events.send(MonitorEvent::Stop);
+
+            // Windows ends the process as soon as the handler returns when the console is closed,
+            // the user logs off or the system shuts down, which would leave the routes behind
+            let (stopped, changed) = &CONSOLE_STOPPED;
+            let mut done = stopped.lock().unwrap_or_else(PoisonError::into_inner);
+            while !*done {
+                done = changed.wait(done).unwrap_or_else(PoisonError::into_inner);
             }
 
-            return;
+            BOOL::from(true)
         }
+        None => BOOL::from(false),
     }
+}
+
+/// Lets the console control handler return once the routes have been removed.
+fn console_stopped() {
+    let (stopped, changed) = &CONSOLE_STOPPED;
+    *stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+    changed.notify_all();
+}
+
+define_windows_service!(ffi_service_main, service_main);
 
-    let (wsl_interface, routes, log_level) = match cli.command {
-        Commands::Run(cli::RunArgs {
-            wsl_interface,
-            routes,
-            log_level,
-        }) => (wsl_interface, routes, log_level),
-        _ => {
-            eprintln!("Unsupported command supplied");
-            if let Err(e) = init_service_logger(LevelFilter::Info) {
+fn service_main(_arguments: Vec<std::ffi::OsString>) {
+    let (config, config_path) = match service_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e.report());
+            if let Err(e) = init_service_logger(LevelFilter::Info, &default_log_dir(), LogRotation::default(), LogFormat::Text) {
                 eprintln!("Failed to initialize logging: {}", e);
             } else {
-                error!("Unsupported command supplied")
+                error!("{}", e.report())
+            }
+
+            if let Err(e) = report_failure(&e) {
+                error!("{}", e.report());
             }
 
             return;
         }
     };
 
-    if let Err(e) = init_service_logger(log_level) {
+    if let Err(e) = init_service_logger(config.log_level, &config.log_dir(), config.log_rotation(), config.log_format) {
         eprintln!("Failed to initialize logging: {}", e);
     }
 
     info!("Running service");
 
-    if let Err(e) = run_service(wsl_interface, routes) {
-        error!("Failed to run service: {}", e);
+    if let Err(e) = run_service(config, config_path) {
+        error!("Failed to run service: {}", e.report());
     } else {
         info!("Stopped running service");
     }
 }
 
-fn run_service(wsl_interface: Option<String>, routes: Vec<Ipv4Network>) -> Result<(), String> {
-    let (stop_sender, stop_receiver) = mpsc::channel();
+/// The configuration of the service from the command line it was installed with.
+fn service_config() -> Result<(Config, Option<PathBuf>), Error> {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => return Err(Error::parse("Commandline parsing failed", e)),
+    };
+
+    match cli.command {
+        Commands::Run(RunCommandArgs { run: args, .. }) => Ok((args.to_config()?, args.config)),
+        _ => Err(Error::config("Unsupported command supplied, the service only runs the run command")),
+    }
+}
+
+/// Reports a service that fails before it runs as stopped with the service specific exit code of
+/// the error, instead of letting the Service Control Manager see it terminate unexpectedly.
+fn report_failure(error: &Error) -> Result<(), Error> {
+    let status_handle = service_control_handler::register(SERVICE_NAME, |_| ServiceControlHandlerResult::NotImplemented)
+        .map_err(|e| Error::scm("Failed to register service control handler", e))?;
+
+    status_handle
+        .set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::Stopped,
+            controls_accepted: ServiceControlAccept::empty(),
+            exit_code: ServiceExitCode::ServiceSpecific(error.service_exit_code()),
+            checkpoint: 0,
+            wait_hint: std::time::Duration::default(),
+            process_id: None,
+        })
+        .map_err(|e| Error::scm("Failed to set service status", e))
+}
+
+fn run_service(config: Config, config_path: Option<PathBuf>) -> Result<(), Error> {
+    let (event_sender, event_receiver) = mpsc::channel();
+    let control_sender = event_sender.clone();
+
+    // Reloads can change whether routes are kept once the monitor has stopped
+    let keep_routes_on_stop = Arc::new(AtomicBool::new(config.keep_routes_on_stop));
+    let has_config_file = config_path.is_some();
+    let control_handler = Arc::new(ControlHandler::new(
+        event_sender.clone(),
+        config_path,
+        keep_routes_on_stop.clone(),
+    ));
+    let reload_handler = control_handler.clone();
+
+    let controls_accepted = if has_config_file {
+        ServiceControlAccept::STOP | ServiceControlAccept::PARAM_CHANGE
+    } else {
+        ServiceControlAccept::STOP
+    };
 
     let event_handler = move |control_event| -> ServiceControlHandlerResult {
         match control_event {
             ServiceControl::Stop => {
-                stop_sender.send(()).unwrap();
+                let _ = control_sender.send(MonitorEvent::Stop);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::ParamChange => {
+                if !has_config_file {
+                    return ServiceControlHandlerResult::NotImplemented;
+                }
+
+                if let Err(e) = reload_handler.reload() {
+                    error!("Keeping the current configuration: {}", e);
+                }
+
                 ServiceControlHandlerResult::NoError
             }
             _ => ServiceControlHandlerResult::NotImplemented,
@@ -85,12 +210,12 @@ fn run_service(wsl_interface: Option<String>, routes: Vec<Ipv4Network>) -> Resul
     };
 
     let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)
-        .map_err(|e| format!("Failed to register service control handler: {}", e))?;
+        .map_err(|e| Error::scm("Failed to register service control handler", e))?;
 
     let service_status = ServiceStatus {
         service_type: ServiceType::OWN_PROCESS,
         current_state: ServiceState::Running,
-        controls_accepted: ServiceControlAccept::STOP,
+        controls_accepted,
         exit_code: ServiceExitCode::Win32(0),
         checkpoint: 0,
         wait_hint: std::time::Duration::default(),
@@ -99,21 +224,76 @@ fn run_service(wsl_interface: Option<String>, routes: Vec<Ipv4Network>) -> Resul
 
     status_handle
         .set_service_status(service_status)
-        .map_err(|e| format!("Failed to set service status: {}", e))?;
+        .map_err(|e| Error::scm("Failed to set service status", e))?;
+
+    // Requests are answered for as long as the process runs
+    thread::spawn(move || match PipeListener::new(PIPE_NAME) {
+        Ok(listener) => control::serve(listener, |request| control_handler.handle(request)),
+        Err(e) => error!("Control requests can't be answered, {} could not be created: {}", PIPE_NAME, e),
+    });
 
-    WslMonitor::new(wsl_interface, routes).start(stop_receiver);
+    let result = run_monitor(config, managed_routes_path, event_sender, event_receiver, &keep_routes_on_stop);
+
+    // A failure is reported to the Service Control Manager as a service specific exit code
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(e) => ServiceExitCode::ServiceSpecific(e.service_exit_code()),
+    };
 
     status_handle
         .set_service_status(ServiceStatus {
             service_type: ServiceType::OWN_PROCESS,
             current_state: ServiceState::Stopped,
             controls_accepted: ServiceControlAccept::empty(),
-            exit_code: ServiceExitCode::Win32(0),
+            exit_code,
             checkpoint: 0,
             wait_hint: std::time::Duration::default(),
             process_id: None,
         })
-        .map_err(|e| format!("Failed to set service status: {}", e))?;
+        .map_err(|e| Error::scm("Failed to set service status", e))?;
+
+    result
+}
+
+/// Runs the monitor until it is stopped, recording its routes in the state file that `state_file`
+/// places in the install directory.
+fn run_monitor(
+    config: Config,
+    state_file: fn(&Path) -> PathBuf,
+    event_sender: mpsc::Sender<MonitorEvent>,
+    mut event_receiver: mpsc::Receiver<MonitorEvent>,
+    keep_routes_on_stop: &AtomicBool,
+) -> Result<(), Error> {
+    let install_dir = std::env::current_exe()
+        .map_err(|e| Error::io("Failed to get service executable path", e))?
+        .parent()
+        .map(|p| p.to_path_buf())
+        .ok_or_else(|| Error::not_found("Service executable has no parent directory"))?;
+
+    doctor::diagnose(&wslconfig::user_configs(), None, &DetectionOptions::from(&config)).log();
+
+    let notifications = NetworkNotifications::register(event_sender)
+        .inspect_err(|e| error!("Network changes will only be picked up by polling: {}", e))
+        .ok();
+
+    let mut monitor = WslMonitor::new(
+        IpHelperRouteTable,
+        WslGatewayDetector::new(DetectionOptions::from(&config)),
+        config.vm_routes(),
+    )
+    .with_state_file(state_file(&install_dir));
+    monitor.metric = config.metric;
+    monitor.poll_interval = config.poll_interval();
+    monitor.log_config = Some(config.clone());
+
+    monitor.start(&mut event_receiver);
+    drop(notifications);
+
+    if keep_routes_on_stop.load(Ordering::Relaxed) {
+        info!("Keeping routes in the routing table");
+    } else {
+        monitor.remove_routes();
+    }
 
     Ok(())
 }
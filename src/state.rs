@@ -0,0 +1,74 @@
+use std::{fs, io::ErrorKind, path::Path};
+#[cfg(windows)]
+use std::path::PathBuf;
+
+use crate::{error::Error, routes::Route};
+
+#[cfg(windows)]
+const MANAGED_ROUTES_FILE: &str = "managed-routes.json";
+#[cfg(windows)]
+const FOREGROUND_ROUTES_FILE: &str = "foreground-routes.json";
+
+/// Location of the file recording the routes added by the service installed in `install_dir`.
+#[cfg(windows)]
+pub fn managed_routes_path(install_dir: &Path) -> PathBuf {
+    install_dir.join(MANAGED_ROUTES_FILE)
+}
+
+/// Location of the file recording the routes added by `run --foreground`, kept apart from those of
+/// the service so that neither removes the routes of the other.
+#[cfg(windows)]
+pub fn foreground_routes_path(install_dir: &Path) -> PathBuf {
+    install_dir.join(FOREGROUND_ROUTES_FILE)
+}
+
+/// Loads the recorded routes. A missing file means no routes are managed.
+pub fn load_managed_routes(path: &Path) -> Result<Vec<Route>, Error> {
+    match fs::read_to_string(path) {
+        Ok(s) => serde_json::from_str(&s)
+            .map_err(|e| Error::parse(format!("Failed to parse {}", path.display()), e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(Error::io(format!("Failed to read {}", path.display()), e)),
+    }
+}
+
+/// Records the routes, removing the file when there are none left.
+pub fn save_managed_routes(path: &Path, routes: &[Route]) -> Result<(), Error> {
+    if routes.is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(Error::io(format!("Failed to remove {}", path.display()), e))
+            }
+            _ => Ok(()),
+        };
+    }
+
+    let s = serde_json::to_string_pretty(routes)
+        .map_err(|e| Error::parse("Failed to serialize managed routes", e))?;
+    fs::write(path, s).map_err(|e| Error::io(format!("Failed to write {}", path.display()), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    #[test]
+    fn managed_routes_round_trip() {
+        let path = std::env::temp_dir().join(format!("route2wsl-state-{}.json", std::process::id()));
+        let routes = vec![Route {
+            destination: "10.152.183.0/24".parse().unwrap(),
+            interface_index: 12,
+            next_hop: IpAddr::from([172, 20, 0, 1]),
+            metric: 1,
+        }];
+
+        save_managed_routes(&path, &routes).unwrap();
+        assert_eq!(load_managed_routes(&path).unwrap(), routes);
+
+        save_managed_routes(&path, &[]).unwrap();
+        assert!(!path.exists());
+        assert!(load_managed_routes(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr},
+};
+#[cfg(windows)]
+use std::net::Ipv6Addr;
+
+use serde::Serialize;
+
+use crate::routes::{Gateway, Route, RouteSpec, routes_for_gateway};
+#[cfg(windows)]
+use crate::{
+    config::VmRoutes,
+    output::Report,
+    wsl_monitor::{Detection, DetectionOptions, MonitorStatus, detect_interfaces},
+};
+
+/// Live state of the service, its VMs and their routes, printed by `status`.
+#[cfg(windows)]
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    /// State of the service as reported by the Service Control Manager.
+    pub service_state: ServiceState,
+    /// What the running service has found and done, as it answered over the control pipe.
+    pub monitor: Option<MonitorStatus>,
+    /// Why the running service did not answer.
+    pub monitor_error: Option<String>,
+    pub vms: Vec<VmStatus>,
+}
+
+#[cfg(windows)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Stopped,
+    StartPending,
+    StopPending,
+    Running,
+    ContinuePending,
+    PausePending,
+    Paused,
+}
+
+#[cfg(windows)]
+#[derive(Debug, Clone, Serialize)]
+pub struct VmStatus {
+    /// The VM as `owner` or `owner/name`.
+    pub vm: String,
+    pub detection: Option<Detection>,
+    pub interfaces: Vec<InterfaceStatus>,
+    /// Why the VM or its interfaces could not be found.
+    pub error: Option<String>,
+    pub routes: Vec<RouteCheck>,
+}
+
+/// A host interface that routes are sent through.
+#[cfg(windows)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InterfaceStatus {
+    pub name: String,
+    pub index: u32,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// Whether a configured route is in the routing table as expected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RouteCheck {
+    pub route: RouteSpec,
+    /// The row the route should have, if its VM and a next hop were found.
+    pub expected: Option<Route>,
+    /// The row in the routing table for the destination, preferring the expected one.
+    pub actual: Option<Route>,
+    pub state: RouteState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteState {
+    /// The expected row is in the routing table.
+    Present,
+    /// The expected row is in the routing table with another metric.
+    WrongMetric,
+    /// The destination is only routed through another interface or next hop.
+    WrongNextHop,
+    /// The destination is not in the routing table.
+    Missing,
+    /// The VM or a gateway address of the route's address family was not found.
+    NoGateway,
+}
+
+/// Looks up the interfaces of a VM and checks its routes against the routing table.
+#[cfg(windows)]
+pub fn vm_status(vm: &VmRoutes, options: &DetectionOptions, metric: u32, actual: &[Route]) -> VmStatus {
+    let mut status = VmStatus {
+        vm: vm.vm(),
+        detection: None,
+        interfaces: vec![],
+        error: None,
+        routes: vec![],
+    };
+
+    let detection = match detect_interfaces(vm, options) {
+        Ok(detection) => detection,
+        Err(e) => {
+            status.error = Some(e.report());
+            status.routes = check_routes(&vm.routes, &[], metric, actual);
+            return status;
+        }
+    };
+
+    let mut gateways = vec![];
+
+    for name in &detection.interfaces {
+        match detection.gateway(name) {
+            Ok(gateway) => {
+                status.interfaces.push(InterfaceStatus {
+                    name: name.clone(),
+                    index: gateway.interface_index,
+                    ipv4: gateway.ipv4,
+                    ipv6: gateway.ipv6,
+                });
+                gateways.push(gateway);
+            }
+            Err(e) => status.error = Some(e.report()),
+        }
+    }
+
+    status.detection = Some(detection);
+    status.routes = check_routes(&vm.routes, &gateways, metric, actual);
+    status
+}
+
+/// Checks each route through each gateway against the rows of the routing table.
+pub fn check_routes(
+    routes: &[RouteSpec],
+    gateways: &[Gateway],
+    metric: u32,
+    actual: &[Route],
+) -> Vec<RouteCheck> {
+    let mut checks = vec![];
+
+    for route in routes {
+        let expected: Vec<Route> = gateways
+            .iter()
+            .flat_map(|gateway| routes_for_gateway(gateway, std::slice::from_ref(route), metric))
+            .collect();
+
+        if expected.is_empty() {
+            checks.push(RouteCheck {
+                route: *route,
+                expected: None,
+                actual: actual.iter().find(|a| a.destination == route.destination).cloned(),
+                state: RouteState::NoGateway,
+            });
+        }
+
+        for expected in expected {
+            checks.push(check_route(*route, expected, actual));
+        }
+    }
+
+    checks
+}
+
+fn check_route(route: RouteSpec, expected: Route, actual: &[Route]) -> RouteCheck {
+    let (actual, state) = match actual.iter().find(|a| a.same_row(&expected)) {
+        Some(row) if row.metric == expected.metric => (Some(row.clone()), RouteState::Present),
+        Some(row) => (Some(row.clone()), RouteState::WrongMetric),
+        None => match actual.iter().find(|a| a.destination == expected.destination) {
+            Some(row) => (Some(row.clone()), RouteState::WrongNextHop),
+            None => (None, RouteState::Missing),
+        },
+    };
+
+    RouteCheck {
+        route,
+        expected: Some(expected),
+        actual,
+        state,
+    }
+}
+
+#[cfg(windows)]
+impl Report for Status {
+    fn text(&self) -> String {
+        let mut lines = vec![format!("Service: {}", self.service_state)];
+
+        if let Some(monitor) = &self.monitor {
+            for report in &monitor.routes {
+                lines.push(format!(
+                    "   Route {} via {} on interface {}: {}",
+                    report.route.destination, report.route.next_hop, report.route.interface_index, report.status
+                ));
+            }
+        }
+        if let Some(error) = &self.monitor_error {
+            lines.push(format!("   Error: {}", error));
+        }
+
+        for vm in &self.vms {
+            lines.push(format!("VM {}:", vm.vm));
+
+            if let Some(detection) = &vm.detection {
+                lines.push(format!("   Networking: {}", detection.mode));
+
+                if let Some(guest_address) = detection.guest_address {
+                    lines.push(format!("   Guest Address: {}", guest_address));
+                }
+                if detection.configured {
+                    lines.push(String::from("   Interface: configured"));
+                }
+                if !detection.vm_ids.is_empty() {
+                    lines.push(format!("   VM Ids: {}", detection.vm_ids.join(", ")));
+                }
+                if !detection.endpoints.is_empty() {
+                    lines.push(format!("   HCN Endpoints: {}", detection.endpoints.join(", ")));
+                }
+                if let Some(network) = &detection.network {
+                    lines.push(format!("   HCN Network: {}", network));
+                }
+            }
+
+            for interface in &vm.interfaces {
+                let addresses: Vec<String> = interface
+                    .ipv4
+                    .map(IpAddr::V4)
+                    .into_iter()
+                    .chain(interface.ipv6.map(IpAddr::V6))
+                    .map(|a| a.to_string())
+                    .collect();
+
+                lines.push(format!(
+                    "   Interface: {} (index {}) gateway {}",
+                    interface.name,
+                    interface.index,
+                    addresses.join(", ")
+                ));
+            }
+
+            if let Some(error) = &vm.error {
+                lines.push(format!("   Error: {}", error));
+            }
+
+            for check in &vm.routes {
+                lines.push(format!("   {}", check));
+            }
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(windows)]
+impl Display for ServiceState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            ServiceState::Stopped => "stopped",
+            ServiceState::StartPending => "start pending",
+            ServiceState::StopPending => "stop pending",
+            ServiceState::Running => "running",
+            ServiceState::ContinuePending => "continue pending",
+            ServiceState::PausePending => "pause pending",
+            ServiceState::Paused => "paused",
+        };
+
+        f.write_str(state)
+    }
+}
+
+#[cfg(windows)]
+impl From<windows_service::service::ServiceState> for ServiceState {
+    fn from(state: windows_service::service::ServiceState) -> Self {
+        use windows_service::service::ServiceState as Scm;
+
+        match state {
+            Scm::Stopped => ServiceState::Stopped,
+            Scm::StartPending => ServiceState::StartPending,
+            Scm::StopPending => ServiceState::StopPending,
+            Scm::Running => ServiceState::Running,
+            Scm::ContinuePending => ServiceState::ContinuePending,
+            Scm::PausePending => ServiceState::PausePending,
+            Scm::Paused => ServiceState::Paused,
+        }
+    }
+}
+
+impl Display for RouteCheck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.state, &self.expected, &self.actual) {
+            (RouteState::Present, Some(expected), _) => write!(
+                f,
+                "{}: present via {} metric {}",
+                self.route, expected.next_hop, expected.metric
+            ),
+            (RouteState::WrongMetric, Some(expected), Some(actual)) => write!(
+                f,
+                "{}: metric {} instead of {}",
+                self.route, actual.metric, expected.metric
+            ),
+            (RouteState::WrongNextHop, Some(expected), Some(actual)) => write!(
+                f,
+                "{}: via {} on interface {} instead of {} on interface {}",
+                self.route, actual.next_hop, actual.interface_index, expected.next_hop, expected.interface_index
+            ),
+            (RouteState::NoGateway, _, _) => write!(f, "{}: no gateway", self.route),
+            _ => write!(f, "{}: missing", self.route),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(destination: &str, interface_index: u32, next_hop: [u8; 4], metric: u32) -> Route {
+        Route {
+            destination: destination.parse().unwrap(),
+            interface_index,
+            next_hop: IpAddr::V4(Ipv4Addr::from(next_hop)),
+            metric,
+        }
+    }
+
+    #[test]
+    fn check_routes_compares_next_hop_and_metric() {
+        let gateway = Gateway {
+            interface_index: 12,
+            ipv4: Some(Ipv4Addr::new(172, 20, 0, 1)),
+            ipv6: None,
+        };
+        let routes: Vec<RouteSpec> = ["10.1.0.0/16", "10.2.0.0/16", "10.3.0.0/16", "10.4.0.0/16", "fd00::/64"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        let actual = vec![
+            row("10.1.0.0/16", 12, [172, 20, 0, 1], 1),
+            row("10.2.0.0/16", 12, [172, 20, 0, 1], 7),
+            row("10.3.0.0/16", 4, [192, 168, 1, 1], 1),
+        ];
+
+        let states: Vec<RouteState> = check_routes(&routes, &[gateway], 1, &actual)
+            .iter()
+            .map(|c| c.state)
+            .collect();
+
+        assert_eq!(
+            states,
+            vec![
+                RouteState::Present,
+                RouteState::WrongMetric,
+                RouteState::WrongNextHop,
+                RouteState::Missing,
+                RouteState::NoGateway,
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_of_an_undetected_vm_have_no_gateway() {
+        let routes: Vec<RouteSpec> = vec!["10.1.0.0/16".parse().unwrap()];
+        let actual = vec![row("10.1.0.0/16", 12, [172, 20, 0, 1], 1)];
+
+        let checks = check_routes(&routes, &[], 1, &actual);
+
+        assert_eq!(checks[0].state, RouteState::NoGateway);
+        assert_eq!(checks[0].actual, Some(actual[0].clone()));
+    }
+}
@@ -1,131 +1,979 @@
-use std::{fmt::Debug, net::IpAddr, sync::mpsc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    net::IpAddr,
+    path::PathBuf,
+    sync::mpsc,
+    time::Duration,
+};
+
+use log::{Level, debug, error, info, log, warn};
+use serde::{Deserialize, Serialize};
 
-use ipnetwork::Ipv4Network;
-use log::debug;
-use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use crate::{
+    config::{Config, DEFAULT_METRIC, DEFAULT_POLL_INTERVAL_SECS, NetworkingMode, VmRoutes, WSL_OWNER},
+    control::{MonitorRequest, Response},
+    error::Error,
+    interfaces::HostInterface,
+    routes::{
+        Gateway, Route, RouteStatus, RouteTable, reconcile_routes, remove_routes,
+        routes_for_gateway,
+    },
+    state::save_managed_routes,
+};
 
+use crate::hcn::Endpoint;
+#[cfg(windows)]
 use crate::{
-    hcn::{Endpoint, list_endpoints},
-    hcs::get_virtual_machine_id,
-    routes::add_routes,
+    hcn::{WSL_NETWORK_NAMES, list_endpoints, list_networks, select_network},
+    hcs::get_virtual_machine_ids,
+    interfaces,
+    state::load_managed_routes,
+    wslconfig,
 };
 
+/// How long to wait for a burst of network change notifications to end before acting on them.
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// Something the monitor reacts to.
+#[derive(Debug, Clone)]
+pub enum MonitorEvent {
+    /// Network interfaces, addresses or routes have changed.
+    NetworkChanged,
+    /// The configuration has changed and should be applied without restarting.
+    Reload(Box<Config>),
+    /// A request from the control channel, answered on the sender.
+    Control(MonitorRequest, mpsc::Sender<Response>),
+    /// The monitor should stop.
+    Stop,
+}
+
+/// Source of the events that drive the monitor.
+pub trait EventSource {
+    /// Waits up to `timeout` for the next event. Returns `None` when the timeout elapses.
+    fn next_event(&mut self, timeout: Duration) -> Option<MonitorEvent>;
+}
+
+impl EventSource for mpsc::Receiver<MonitorEvent> {
+    fn next_event(&mut self, timeout: Duration) -> Option<MonitorEvent> {
+        match self.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => Some(MonitorEvent::Stop),
+        }
+    }
+}
+
+/// Finds the gateways that routes should be sent through.
+pub trait GatewayDetector {
+    /// Returns a gateway for each running VM that the routes are sent through.
+    fn detect(&mut self, vm: &VmRoutes) -> Result<Vec<Gateway>, Error>;
+
+    /// Applies the detection options of a reloaded configuration.
+    fn configure(&mut self, config: &Config);
+
+    /// Forgets what was detected before, so the next detection starts over.
+    fn reset(&mut self);
+}
+
+/// How VMs are detected, taken from the configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DetectionOptions {
+    /// Name of the WSL interface, used instead of detecting it.
+    pub wsl_interface: Option<String>,
+    pub networking_mode: NetworkingMode,
+    /// Address of the WSL VM that routes are sent to in mirrored mode.
+    pub wsl_guest_address: Option<IpAddr>,
+}
+
+impl DetectionOptions {
+    /// The configured networking mode, or the one of `.wslconfig` when it is automatic.
+    #[cfg(windows)]
+    pub fn resolved_networking_mode(&self) -> NetworkingMode {
+        match self.networking_mode {
+            NetworkingMode::Auto => wslconfig::detect_networking_mode(),
+            mode => mode,
+        }
+    }
+}
+
+impl From<&Config> for DetectionOptions {
+    fn from(config: &Config) -> Self {
+        DetectionOptions {
+            wsl_interface: config.wsl_interface.clone(),
+            networking_mode: config.networking_mode,
+            wsl_guest_address: config.wsl_guest_address,
+        }
+    }
+}
+
+/// Detects the host side of the network of each VM, by looking up the VM's HCN endpoints.
+/// The WSL interface can be configured by name instead.
+#[cfg(windows)]
 #[derive(Debug)]
-pub struct WslMonitor {
-    pub wsl_interface_name: Option<String>,
-    pub routes: Vec<Ipv4Network>,
+pub struct WslGatewayDetector {
+    options: DetectionOptions,
+    resolved_interfaces: HashMap<String, Vec<String>>,
+    /// The networking mode last detected for each VM, to log when it changes.
+    modes: HashMap<String, NetworkingMode>,
+}
+
+#[cfg(windows)]
+impl WslGatewayDetector {
+    pub fn new(options: DetectionOptions) -> Self {
+        WslGatewayDetector {
+            options,
+            resolved_interfaces: HashMap::new(),
+            modes: HashMap::new(),
+        }
+    }
 }
 
-impl WslMonitor {
-    pub fn new(wsl_interface_name: Option<String>, routes: Vec<Ipv4Network>) -> Self {
+#[cfg(windows)]
+impl GatewayDetector for WslGatewayDetector {
+    fn detect(&mut self, vm: &VmRoutes) -> Result<Vec<Gateway>, Error> {
+        let key = vm.vm();
+
+        if let Some(interface_names) = self.resolved_interfaces.get(&key) {
+            return interface_names
+                .iter()
+                .map(|name| get_gateway(name))
+                .collect::<Result<Vec<Gateway>, Error>>()
+                .inspect_err(|_| {
+                    // The VM may have recreated its interface under a different name
+                    self.resolved_interfaces.remove(&key);
+                });
+        }
+
+        let detection = detect_interfaces(vm, &self.options)?;
+
+        if self.modes.insert(key.clone(), detection.mode) != Some(detection.mode) {
+            info!(
+                vm:% = key, vm_id:% = detection.vm_ids.join(","), mode:% = detection.mode;
+                "{} uses {} networking", key, detection.mode
+            );
+        }
+
+        let gateways = detection
+            .interfaces
+            .iter()
+            .map(|name| detection.gateway(name))
+            .collect::<Result<Vec<Gateway>, Error>>()?;
+
+        // Configured interfaces and mirrored mode are looked up again on every check
+        if !detection.configured && detection.guest_address.is_none() {
+            debug!(
+                vm:% = key, vm_id:% = detection.vm_ids.join(","), interface:% = detection.interfaces.join(",");
+                "Auto detected {} interfaces: {}", key, detection.interfaces.join(", ")
+            );
+            self.resolved_interfaces.insert(key, detection.interfaces);
+        }
+
+        Ok(gateways)
+    }
+
+    fn configure(&mut self, config: &Config) {
+        let options = DetectionOptions::from(config);
+
+        if self.options != options {
+            *self = WslGatewayDetector::new(options);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.resolved_interfaces.clear();
+    }
+}
+
+/// What the monitor has found and done, answered to a status request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitorStatus {
+    /// The gateways of each VM that was found.
+    pub gateways: BTreeMap<String, Vec<Gateway>>,
+    /// The outcome of the last reconciliation for each configured route.
+    pub routes: Vec<RouteReport>,
+    /// The routes added by the monitor.
+    pub managed_routes: Vec<Route>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteReport {
+    pub route: Route,
+    pub status: RouteStatus,
+}
+
+#[derive(Debug)]
+pub struct WslMonitor<R: RouteTable, D: GatewayDetector> {
+    pub route_table: R,
+    pub detector: D,
+    /// The routes of each VM.
+    pub vms: Vec<VmRoutes>,
+    pub metric: u32,
+    /// How often the routing table is checked when no network change has been notified.
+    pub poll_interval: Duration,
+    gateways: BTreeMap<String, Vec<Gateway>>,
+    managed_routes: Vec<Route>,
+    route_status: Vec<(Route, RouteStatus)>,
+    state_file: Option<PathBuf>,
+    /// The configuration the log was set up with, to tell when a reload changes it.
+    pub log_config: Option<Config>,
+}
+
+impl<R: RouteTable, D: GatewayDetector> WslMonitor<R, D> {
+    pub fn new(route_table: R, detector: D, vms: Vec<VmRoutes>) -> Self {
         WslMonitor {
-            wsl_interface_name,
-            routes,
+            route_table,
+            detector,
+            vms,
+            metric: DEFAULT_METRIC,
+            poll_interval: Duration::from_secs(DEFAULT_POLL_INTERVAL_SECS),
+            gateways: BTreeMap::new(),
+            managed_routes: vec![],
+            route_status: vec![],
+            state_file: None,
+            log_config: None,
         }
     }
 
-    pub fn start(&self, stop_receiver: mpsc::Receiver<()>) {
-        let mut resolved_interface: Option<String> = self.wsl_interface_name.clone();
-        let mut resolved_ipaddress: Option<IpAddr> = None;
+    /// Records the routes added by the monitor in `path`, picking up the routes recorded by a previous run.
+    #[cfg(windows)]
+    pub fn with_state_file(mut self, path: PathBuf) -> Self {
+        match load_managed_routes(&path) {
+            Ok(routes) => self.managed_routes = routes,
+            Err(e) => error!("Failed to load managed routes: {}", e),
+        }
+
+        self.state_file = Some(path);
+        self
+    }
+
+    /// Keeps the routes in place until a stop event is received. The routing table is checked
+    /// whenever the network changes, and every `poll_interval` in case a change was missed.
+    pub fn start(&mut self, events: &mut impl EventSource) {
+        self.check();
 
         loop {
-            let current_interface = resolved_interface.clone();
-            match current_interface {
-                None => match find_wsl_interface() {
-                    Ok(val) => {
-                        resolved_interface = Some(val.clone());
-                        debug!("Auto detected wsl interface: {}", val.clone());
+            let mut event = events.next_event(self.poll_interval);
+            let mut network_changed = false;
+
+            // Let a burst of network changes end before acting on it
+            while matches!(event, Some(MonitorEvent::NetworkChanged)) {
+                network_changed = true;
+                event = events.next_event(SETTLE_DELAY);
+            }
+
+            match event {
+                Some(MonitorEvent::Stop) => break,
+                Some(MonitorEvent::Reload(config)) => self.apply_config(&config),
+                Some(MonitorEvent::Control(request, reply)) => {
+                    let _ = reply.send(self.handle_request(request));
+
+                    // Answering a request is no reason to check the routing table
+                    if !network_changed {
                         continue;
                     }
-                    Err(e) => {
-                        debug!("Error finding wsl interface: {}", e)
+                }
+                _ => {}
+            }
+
+            self.check();
+        }
+    }
+
+    /// Answers a request from the control channel.
+    pub fn handle_request(&mut self, request: MonitorRequest) -> Response {
+        match request {
+            MonitorRequest::Status => Response::ok(self.status()),
+            MonitorRequest::ManagedRoutes => Response::ok(&self.managed_routes),
+            MonitorRequest::Resync => {
+                info!("Detecting VMs again on request");
+                self.detector.reset();
+                self.check();
+                Response::ok(self.status())
+            }
+            MonitorRequest::SetLogLevel { level } => {
+                info!("Log level set to {} on request", level);
+                log::set_max_level(level);
+                Response::ok(level)
+            }
+        }
+    }
+
+    pub fn status(&self) -> MonitorStatus {
+        MonitorStatus {
+            gateways: self.gateways.clone(),
+            routes: self
+                .route_status
+                .iter()
+                .map(|(route, status)| RouteReport {
+                    route: route.clone(),
+                    status: status.clone(),
+                })
+                .collect(),
+            managed_routes: self.managed_routes.clone(),
+        }
+    }
+
+    /// Takes on the routes and options of the configuration. The next check adds the new
+    /// routes and removes the ones that are no longer configured.
+    pub fn apply_config(&mut self, config: &Config) {
+        info!("Applying reloaded configuration");
+
+        if self.log_config.as_ref().is_some_and(|running| running.logging_differs(config)) {
+            warn!("The log directory, format and rotation settings take effect when the service restarts");
+        }
+
+        log::set_max_level(config.log_level);
+        self.detector.configure(config);
+        self.vms = config.vm_routes();
+        self.metric = config.metric;
+        self.poll_interval = config.poll_interval();
+    }
+
+    fn check(&mut self) {
+        let mut gateways = BTreeMap::new();
+
+        for vm in &self.vms {
+            if gateways.contains_key(&vm.vm()) {
+                continue;
+            }
+
+            match self.detector.detect(vm) {
+                Ok(found) => {
+                    gateways.insert(vm.vm(), found);
+                }
+                Err(e) => debug!(vm:% = vm.vm(), error_code = e.code(); "{}", e),
+            }
+        }
+
+        self.reconcile(gateways);
+    }
+
+    /// Makes sure every configured route is in the routing table through the gateways of its VM,
+    /// removing the routes installed for previous gateways. Routes of VMs that were not found
+    /// are left as they are until the VM is found again.
+    fn reconcile(&mut self, gateways: BTreeMap<String, Vec<Gateway>>) {
+        for (vm, found) in &gateways {
+            if self.gateways.get(vm) != Some(found) {
+                let found: Vec<String> = found.iter().map(|g| g.to_string()).collect();
+                info!(vm:% = vm, gateway:% = found.join("; "); "{} gateway is {}", vm, found.join("; "));
+            }
+        }
+
+        let mut desired = vec![];
+        let mut undetected = vec![];
+
+        for vm in &self.vms {
+            match gateways.get(&vm.vm()) {
+                Some(found) => {
+                    for gateway in found {
+                        desired.extend(routes_for_gateway(gateway, &vm.routes, self.metric));
                     }
-                },
-                Some(interface_name) => {
-                    match get_interface_address(interface_name.clone()) {
-                        Ok(val) => {
-                            let ip_addr = val.addr[0].ip();
-
-                            if resolved_ipaddress.is_none()
-                                || ip_addr != resolved_ipaddress.unwrap()
-                            {
-                                resolved_ipaddress = Some(ip_addr);
-                                add_routes(val, self.routes.clone());
-                            }
-                        }
-                        Err(e) => {
-                            debug!(
-                                "Could not get address if interface {}: {}",
-                                interface_name, e
-                            )
-                        }
-                    };
                 }
+                None => undetected.extend(vm.routes.iter().map(|r| r.destination)),
             }
+        }
+
+        let managed_before = self.managed_routes.clone();
+        let (mut managed, idle): (Vec<Route>, Vec<Route>) = managed_before
+            .iter()
+            .cloned()
+            .partition(|r| !undetected.contains(&r.destination));
 
-            if let Err(e) = stop_receiver.recv_timeout(Duration::from_secs(10)) {
-                let exist = match e {
-                    mpsc::RecvTimeoutError::Timeout => false,
-                    mpsc::RecvTimeoutError::Disconnected => true,
-                };
+        match reconcile_routes(&mut self.route_table, &mut managed, &desired) {
+            Ok(route_status) => {
+                for (route, status) in &route_status {
+                    let level = match status {
+                        // Checked again on every pass, so only logged when debugging
+                        RouteStatus::Present | RouteStatus::NotManaged { .. } => Level::Debug,
+                        // Logged with its error code when it failed
+                        RouteStatus::Failed(_) => continue,
+                        _ => Level::Info,
+                    };
 
-                if exist {
-                    break;
+                    log!(
+                        level,
+                        route:% = route.destination, gateway:% = route.next_hop, interface = route.interface_index, metric = route.metric, status:% = status;
+                        "Route {}: {}", route.destination, status
+                    );
                 }
-            } else {
-                break;
+
+                self.route_status = route_status;
             }
+            Err(e) => error!(error_code = e.code(); "Failed to read the routing table: {}", e),
+        }
+
+        managed.extend(idle);
+        self.managed_routes = managed;
+        self.gateways = gateways;
+
+        if self.managed_routes != managed_before {
+            self.save_state();
         }
     }
+
+    /// Deletes every route added by the monitor.
+    pub fn remove_routes(&mut self) {
+        info!("Removing {} managed route(s)", self.managed_routes.len());
+
+        if let Err(e) = remove_routes(&mut self.route_table, &mut self.managed_routes) {
+            error!(error_code = e.code(); "{}", e);
+        }
+        self.gateways.clear();
+        self.route_status.clear();
+        self.save_state();
+    }
+
+    fn save_state(&self) {
+        if let Some(path) = &self.state_file
+            && let Err(e) = save_managed_routes(path, &self.managed_routes)
+        {
+            error!("Failed to save managed routes: {}", e);
+        }
+    }
+}
+
+/// What was found while looking up the host interfaces of a VM.
+#[cfg(windows)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Detection {
+    /// How the VM is networked.
+    pub mode: NetworkingMode,
+    /// The interface was configured by name instead of being detected.
+    pub configured: bool,
+    /// Ids of the HCS compute systems of the VM.
+    pub vm_ids: Vec<String>,
+    /// Ids of the HCN endpoints of the VM.
+    pub endpoints: Vec<String>,
+    /// Id of the HCN network the interface was found from, when the VM has no endpoint yet.
+    pub network: Option<String>,
+    /// Names of the host interfaces that routes are sent through.
+    pub interfaces: Vec<String>,
+    /// Address of the VM that routes are sent to in mirrored mode.
+    pub guest_address: Option<IpAddr>,
+}
+
+#[cfg(windows)]
+impl Detection {
+    /// The gateway through the named interface. Routes are sent to the guest address in
+    /// mirrored mode, and to the address of the interface otherwise.
+    pub fn gateway(&self, interface_name: &str) -> Result<Gateway, Error> {
+        let mut gateway = get_gateway(interface_name)?;
+
+        if let Some(guest_address) = self.guest_address {
+            gateway.ipv4 = None;
+            gateway.ipv6 = None;
+
+            match guest_address {
+                IpAddr::V4(address) => gateway.ipv4 = Some(address),
+                IpAddr::V6(address) => gateway.ipv6 = Some(address),
+            }
+        }
+
+        Ok(gateway)
+    }
+}
+
+/// Finds the host interfaces of a VM. The WSL interface can be configured by name instead.
+#[cfg(windows)]
+pub fn detect_interfaces(vm: &VmRoutes, options: &DetectionOptions) -> Result<Detection, Error> {
+    let mode = if vm.owner == WSL_OWNER {
+        options.resolved_networking_mode()
+    } else {
+        NetworkingMode::Nat
+    };
+
+    if vm.owner == WSL_OWNER
+        && vm.name.is_none()
+        && let Some(interface_name) = &options.wsl_interface
+    {
+        return Ok(Detection {
+            mode,
+            configured: true,
+            interfaces: vec![interface_name.clone()],
+            guest_address: options.wsl_guest_address.filter(|_| mode == NetworkingMode::Mirrored),
+            ..Detection::default()
+        });
+    }
+
+    let detection = if mode == NetworkingMode::Mirrored {
+        find_mirrored_interface(options.wsl_guest_address)
+    } else {
+        find_vm_interfaces(&vm.owner, vm.name.as_deref()).or_else(|e| {
+            // The WSL network exists before an endpoint is bound to it
+            if vm.owner == WSL_OWNER && vm.name.is_none() {
+                debug!("{}, looking up the WSL network", e);
+                find_network_interface(&WSL_NETWORK_NAMES)
+            } else {
+                Err(e)
+            }
+        })
+    };
+
+    detection
+        .map(|detection| Detection { mode, ..detection })
+        .map_err(|e| e.context(format!("Error finding {} interface", vm.vm())))
+}
+
+/// In mirrored mode the VM shares the interfaces and addresses of the host, so there is no
+/// interface of its own. Routes are sent to an address that only the VM has, through the host
+/// interface on the same network.
+#[cfg(windows)]
+fn find_mirrored_interface(guest_address: Option<IpAddr>) -> Result<Detection, Error> {
+    let guest_address = guest_address.ok_or_else(|| {
+        Error::config("WSL uses mirrored networking, set wsl_guest_address to an address of the WSL VM to send routes to")
+    })?;
+
+    let interfaces = interfaces::list()?;
+
+    if interfaces.iter().any(|i| i.has_address(guest_address)) {
+        return Err(Error::config(format!(
+            "The WSL guest address {} is an address of the host, in mirrored mode it must be an address only the WSL VM has",
+            guest_address
+        )));
+    }
+
+    let interface = interface_for_address(&interfaces, guest_address).ok_or_else(|| {
+        Error::not_found(format!("No host interface is on the network of the WSL guest address {}", guest_address))
+    })?;
+
+    Ok(Detection {
+        mode: NetworkingMode::Mirrored,
+        interfaces: vec![interface.name.clone()],
+        guest_address: Some(guest_address),
+        ..Detection::default()
+    })
 }
 
-fn get_interface_address(interface_name: String) -> Result<NetworkInterface, String> {
-    let interfaces =
-        NetworkInterface::show().map_err(|e| format!("Failed to get network adapters: {}", e))?;
+/// The interface with a subnet that contains the address.
+fn interface_for_address(interfaces: &[HostInterface], address: IpAddr) -> Option<&HostInterface> {
+    interfaces.iter().find(|interface| interface.is_on_network_of(address))
+}
+
+/// Looks up the address of the named interface.
+#[cfg(windows)]
+pub fn get_gateway(interface_name: &str) -> Result<Gateway, Error> {
+    let interface = get_interface_address(interface_name.to_string())
+        .map_err(|e| e.context(format!("Could not get address if interface {}", interface_name)))?;
+
+    Gateway::from_interface(&interface)
+}
 
-    let interfaces: Vec<&NetworkInterface> = interfaces
+#[cfg(windows)]
+fn get_interface_address(interface_name: String) -> Result<HostInterface, Error> {
+    let interfaces = interfaces::list()?;
+
+    let interfaces: Vec<&HostInterface> = interfaces
         .iter()
         .filter(|x| x.name == interface_name)
         .collect();
 
     if interfaces.is_empty() {
-        Err(format!("Could not find interface: {}", interface_name))
+        Err(Error::not_found(format!("Could not find interface: {}", interface_name)))
     } else {
         Ok(interfaces[0].clone())
     }
 }
 
-fn find_wsl_interface() -> Result<String, String> {
-    let vm_id = get_virtual_machine_id("WSL")?;
+/// The names of the host interfaces that are the gateways of the endpoints. Endpoints without a
+/// gateway, or whose gateway is not on this host, are skipped so that they don't keep the other
+/// VMs from being routed.
+fn gateway_interfaces(owner: &str, endpoints: &[&Endpoint], interfaces: &[HostInterface]) -> Result<Vec<String>, Error> {
+    let mut interface_names: Vec<String> = vec![];
+
+    for endpoint in endpoints {
+        let Some(gateway_ip) = endpoint.gateway() else {
+            debug!("Endpoint {} has no gateway address", endpoint.id);
+            continue;
+        };
+
+        let Some(gatway_interface) = interfaces.iter().find(|x| x.has_address(gateway_ip)) else {
+            debug!("Gatway interface for IP Address {} of endpoint {} could not be found", gateway_ip, endpoint.id);
+            continue;
+        };
+
+        // VMs on the same network share a gateway interface
+        if !interface_names.contains(&gatway_interface.name) {
+            interface_names.push(gatway_interface.name.clone());
+        }
+    }
+
+    if interface_names.is_empty() {
+        return Err(Error::not_found(format!("No endpoint of {} VMs has a gateway interface", owner)));
+    }
+
+    Ok(interface_names)
+}
+
+/// Finds the host interface that is the gateway of the first HCN network with one of the names.
+#[cfg(windows)]
+fn find_network_interface(names: &[&str]) -> Result<Detection, Error> {
+    let networks = list_networks()?;
+    let network = select_network(&networks, names)
+        .ok_or_else(|| Error::not_found(format!("Could not find a network named {}", names.join(" or "))))?;
+
+    let subnets: Vec<String> = network
+        .all_subnets()
+        .filter_map(|s| s.prefix())
+        .map(|p| p.to_string())
+        .collect();
+    debug!("Found network {} with subnets {}", network.id, subnets.join(", "));
+
+    let gateway_ip = network
+        .gateway()
+        .ok_or_else(|| Error::not_found(format!("Network {} has no gateway address", network.id)))?;
+
+    let interfaces = interfaces::list()?;
+
+    let interface = interfaces
+        .iter()
+        .find(|x| x.has_address(gateway_ip))
+        .ok_or_else(|| Error::not_found(format!("Gatway interface for IP Address {} could not be found", gateway_ip)))?;
+
+    Ok(Detection {
+        network: Some(network.id.clone()),
+        interfaces: vec![interface.name.clone()],
+        ..Detection::default()
+    })
+}
+
+/// Finds the host interfaces that are the gateways of the endpoints of every VM of the owner,
+/// or of the VM with the given name.
+#[cfg(windows)]
+fn find_vm_interfaces(owner: &str, name: Option<&str>) -> Result<Detection, Error> {
+    let vm_ids = get_virtual_machine_ids(owner, name)?;
     let endpoints = list_endpoints()?;
-    let wsl_endpoints: Vec<&Endpoint> = endpoints
+    let vm_endpoints: Vec<&Endpoint> = endpoints
         .iter()
-        .filter(|x| x.virtual_machine == vm_id)
+        .filter(|x| vm_ids.iter().any(|id| x.is_attached_to(id)))
         .collect();
 
-    if wsl_endpoints.is_empty() {
-        Err(format!("Would not find an endpoint for WSL VM {}", vm_id))
-    } else {
-        let gateway_ip = wsl_endpoints[0]
-            .gateway_address
-            .parse::<IpAddr>()
-            .map_err(|e| format!("Failed to convert VM gateway IP: {}", e))?;
+    if vm_endpoints.is_empty() {
+        return Err(Error::not_found(format!("Would not find an endpoint for {} VMs {}", owner, vm_ids.join(", "))));
+    }
+
+    let interface_names = gateway_interfaces(owner, &vm_endpoints, &interfaces::list()?)?;
+
+    Ok(Detection {
+        mode: NetworkingMode::Nat,
+        endpoints: vm_endpoints.iter().map(|e| e.id.clone()).collect(),
+        vm_ids,
+        interfaces: interface_names,
+        ..Detection::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        net::{IpAddr, Ipv4Addr},
+    };
 
-        let interfaces = NetworkInterface::show()
-            .map_err(|e| format!("Failed to get network adapters: {}", e))?;
+    use super::*;
+    use crate::routes::MemoryRouteTable;
 
-        let gatway_interface: Vec<&NetworkInterface> = interfaces
+    /// Returns the scripted gateways of each VM in order, repeating the last one.
+    #[derive(Default)]
+    struct FakeDetector(HashMap<String, VecDeque<Result<Vec<Gateway>, String>>>);
+
+    impl FakeDetector {
+        fn wsl(gateways: Vec<Result<Gateway, String>>) -> Self {
+            let mut detector = FakeDetector::default();
+            detector.script(WSL_OWNER, gateways.into_iter().map(|g| g.map(|g| vec![g])).collect());
+            detector
+        }
+
+        fn script(&mut self, vm: &str, gateways: Vec<Result<Vec<Gateway>, String>>) {
+            self.0.insert(vm.to_string(), gateways.into());
+        }
+    }
+
+    impl GatewayDetector for FakeDetector {
+        fn detect(&mut self, vm: &VmRoutes) -> Result<Vec<Gateway>, Error> {
+            let gateways = self
+                .0
+                .get_mut(&vm.vm())
+                .ok_or(Error::not_found(format!("{} is not running", vm.vm())))?;
+
+            let next = if gateways.len() > 1 {
+                gateways.pop_front().unwrap()
+            } else {
+                gateways[0].clone()
+            };
+
+            next.map_err(Error::NotFound)
+        }
+
+        fn configure(&mut self, _config: &Config) {}
+
+        fn reset(&mut self) {}
+    }
+
+    /// Returns the scripted events in order, then stops.
+    struct ScriptedEvents(VecDeque<Option<MonitorEvent>>);
+
+    impl EventSource for ScriptedEvents {
+        fn next_event(&mut self, _timeout: Duration) -> Option<MonitorEvent> {
+            self.0.pop_front().unwrap_or(Some(MonitorEvent::Stop))
+        }
+    }
+
+    fn gateway(interface_index: u32, address: [u8; 4]) -> Gateway {
+        Gateway {
+            interface_index,
+            ipv4: Some(Ipv4Addr::from(address)),
+            ipv6: None,
+        }
+    }
+
+    fn monitor(
+        table: MemoryRouteTable,
+        gateways: Vec<Result<Gateway, String>>,
+    ) -> WslMonitor<MemoryRouteTable, FakeDetector> {
+        WslMonitor::new(
+            table,
+            FakeDetector::wsl(gateways),
+            vec![vm_routes(WSL_OWNER, &["10.152.183.0/24"])],
+        )
+    }
+
+    fn vm_routes(owner: &str, routes: &[&str]) -> VmRoutes {
+        VmRoutes {
+            owner: owner.to_string(),
+            name: None,
+            routes: routes.iter().map(|r| r.parse().unwrap()).collect(),
+        }
+    }
+
+    fn wsl(gateway: Gateway) -> BTreeMap<String, Vec<Gateway>> {
+        BTreeMap::from([(WSL_OWNER.to_string(), vec![gateway])])
+    }
+
+    #[test]
+    fn network_change_moves_routes_to_the_new_gateway() {
+        let mut monitor = monitor(
+            MemoryRouteTable::default(),
+            vec![Ok(gateway(12, [172, 20, 0, 1])), Ok(gateway(15, [172, 28, 0, 1]))],
+        );
+
+        monitor.start(&mut ScriptedEvents(
+            vec![Some(MonitorEvent::NetworkChanged), None].into(),
+        ));
+
+        let expected = vec![Route {
+            destination: "10.152.183.0/24".parse().unwrap(),
+            interface_index: 15,
+            next_hop: IpAddr::from([172, 28, 0, 1]),
+            metric: 1,
+        }];
+
+        assert_eq!(monitor.route_table.routes, expected);
+        assert_eq!(monitor.managed_routes, expected);
+    }
+
+    #[test]
+    fn routes_are_added_once_wsl_is_detected() {
+        let mut monitor = monitor(
+            MemoryRouteTable::default(),
+            vec![Err(String::from("WSL is not running")), Ok(gateway(12, [172, 20, 0, 1]))],
+        );
+
+        monitor.start(&mut ScriptedEvents(vec![None].into()));
+
+        assert_eq!(monitor.route_table.routes.len(), 1);
+    }
+
+    #[test]
+    fn stop_during_a_burst_of_changes_ends_the_monitor() {
+        let mut monitor = monitor(
+            MemoryRouteTable::default(),
+            vec![Err(String::from("WSL is not running")), Ok(gateway(12, [172, 20, 0, 1]))],
+        );
+
+        monitor.start(&mut ScriptedEvents(
+            vec![
+                Some(MonitorEvent::NetworkChanged),
+                Some(MonitorEvent::NetworkChanged),
+                Some(MonitorEvent::Stop),
+                None,
+            ]
+            .into(),
+        ));
+
+        assert!(monitor.route_table.routes.is_empty());
+    }
+
+    #[test]
+    fn reload_applies_only_the_route_changes() {
+        let mut monitor = monitor(MemoryRouteTable::default(), vec![Ok(gateway(12, [172, 20, 0, 1]))]);
+        monitor.vms[0].routes.push("10.1.0.0/16".parse().unwrap());
+        monitor.check();
+
+        let config: Config = serde_json::from_str(r#"{"routes": ["10.1.0.0/16", "10.96.0.0/12"]}"#).unwrap();
+
+        monitor.start(&mut ScriptedEvents(
+            vec![Some(MonitorEvent::Reload(Box::new(config)))].into(),
+        ));
+
+        let destinations: Vec<String> = monitor
+            .route_table
+            .routes
             .iter()
-            .filter(|x| !x.addr.is_empty() && x.addr.iter().any(|y| y.ip() == gateway_ip))
+            .map(|r| r.destination.to_string())
             .collect();
 
-        if gatway_interface.is_empty() {
-            Err(String::from(format!(
-                "Gatway interface for IP Address {} could not be found",
-                wsl_endpoints[0].gateway_address
-            )))
-        } else {
-            Ok(gatway_interface[0].name.clone())
-        }
+        assert_eq!(destinations, vec!["10.1.0.0/16", "10.96.0.0/12"]);
+        assert_eq!(
+            monitor.status().routes[0].status,
+            RouteStatus::Present,
+            "unchanged routes are left in place"
+        );
+    }
+
+    #[test]
+    fn routes_deleted_by_someone_else_are_restored() {
+        let mut monitor = monitor(MemoryRouteTable::default(), vec![]);
+
+        monitor.reconcile(wsl(gateway(12, [172, 20, 0, 1])));
+        monitor.route_table.routes.clear();
+        monitor.reconcile(wsl(gateway(12, [172, 20, 0, 1])));
+
+        assert_eq!(monitor.route_table.routes.len(), 1);
+        assert_eq!(monitor.status().routes[0].status, RouteStatus::Added);
+    }
+
+    #[test]
+    fn control_requests_are_answered_by_the_monitor() {
+        let mut monitor = monitor(MemoryRouteTable::default(), vec![Ok(gateway(12, [172, 20, 0, 1]))]);
+        let (reply, answer) = mpsc::channel();
+
+        monitor.start(&mut ScriptedEvents(
+            vec![Some(MonitorEvent::Control(MonitorRequest::ManagedRoutes, reply))].into(),
+        ));
+
+        let managed: Vec<Route> = serde_json::from_value(answer.recv().unwrap().result).unwrap();
+        assert_eq!(managed, monitor.route_table.routes);
+    }
+
+    #[test]
+    fn mirrored_routes_go_through_the_interface_on_the_guest_network() {
+        use crate::interfaces::InterfaceAddress;
+
+        let interface = |name: &str, index, ip: [u8; 4], netmask: [u8; 4]| HostInterface {
+            name: String::from(name),
+            index,
+            addresses: vec![InterfaceAddress {
+                ip: IpAddr::from(ip),
+                netmask: Some(IpAddr::from(netmask)),
+            }],
+        };
+        let interfaces = vec![
+            interface("Loopback Pseudo-Interface 1", 1, [127, 0, 0, 1], [255, 0, 0, 0]),
+            interface("Ethernet", 7, [192, 168, 1, 10], [255, 255, 255, 0]),
+        ];
+
+        let found = interface_for_address(&interfaces, "192.168.1.20".parse().unwrap());
+
+        assert_eq!(found.map(|i| i.index), Some(7));
+        assert!(interface_for_address(&interfaces, "10.0.0.1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn endpoints_whose_gateway_interface_is_missing_are_skipped() {
+        use crate::{hcn::parse_endpoint, interfaces::InterfaceAddress};
+
+        let stale = parse_endpoint(include_str!("../tests/fixtures/hcn/endpoint-v2.json")).unwrap();
+        let wsl = parse_endpoint(include_str!("../tests/fixtures/hcn/endpoint-wsl.json")).unwrap();
+        let interfaces = vec![HostInterface {
+            name: String::from("vEthernet (WSL)"),
+            index: 12,
+            addresses: vec![InterfaceAddress {
+                ip: IpAddr::from([172, 20, 0, 1]),
+                netmask: Some(IpAddr::from([255, 255, 240, 0])),
+            }],
+        }];
+
+        assert_eq!(
+            gateway_interfaces(WSL_OWNER, &[&stale, &wsl], &interfaces).unwrap(),
+            vec!["vEthernet (WSL)"]
+        );
+        assert!(gateway_interfaces(WSL_OWNER, &[&stale], &interfaces).is_err());
+    }
+
+    #[test]
+    fn remove_routes_clears_only_managed_routes() {
+        let user_route = Route {
+            destination: "10.1.0.0/16".parse().unwrap(),
+            interface_index: 4,
+            next_hop: IpAddr::from([192, 168, 1, 1]),
+            metric: 1,
+        };
+        let mut monitor = monitor(
+            MemoryRouteTable {
+                routes: vec![user_route.clone()],
+            },
+            vec![],
+        );
+
+        monitor.reconcile(wsl(gateway(12, [172, 20, 0, 1])));
+        monitor.remove_routes();
+
+        assert_eq!(monitor.route_table.routes, vec![user_route]);
+        assert!(monitor.managed_routes.is_empty());
+    }
+
+    #[test]
+    fn routes_are_sent_through_every_vm_of_their_owner() {
+        let mut monitor = monitor(MemoryRouteTable::default(), vec![Ok(gateway(12, [172, 20, 0, 1]))]);
+        monitor.vms.push(vm_routes("DockerDesktopVM", &["10.2.0.0/16"]));
+        monitor.detector.script(
+            "DockerDesktopVM",
+            vec![Ok(vec![gateway(20, [172, 30, 0, 1]), gateway(21, [172, 31, 0, 1])])],
+        );
+
+        monitor.check();
+
+        let rows: Vec<(String, u32)> = monitor
+            .route_table
+            .routes
+            .iter()
+            .map(|r| (r.destination.to_string(), r.interface_index))
+            .collect();
+
+        assert_eq!(
+            rows,
+            vec![
+                (String::from("10.152.183.0/24"), 12),
+                (String::from("10.2.0.0/16"), 20),
+                (String::from("10.2.0.0/16"), 21),
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_of_a_vm_that_is_not_found_are_left_in_place() {
+        let mut monitor = monitor(MemoryRouteTable::default(), vec![Ok(gateway(12, [172, 20, 0, 1]))]);
+        monitor.vms.push(vm_routes("DockerDesktopVM", &["10.2.0.0/16"]));
+        monitor.detector.script(
+            "DockerDesktopVM",
+            vec![
+                Ok(vec![gateway(20, [172, 30, 0, 1])]),
+                Err(String::from("DockerDesktopVM is not running")),
+            ],
+        );
+
+        monitor.check();
+        monitor.check();
+
+        assert_eq!(monitor.route_table.routes.len(), 2);
+        assert_eq!(monitor.managed_routes.len(), 2);
+
+        // Routes of a VM that is no longer configured are removed even though it is not running
+        monitor.vms.pop();
+        monitor.check();
+
+        assert_eq!(monitor.route_table.routes.len(), 1);
+        assert_eq!(monitor.managed_routes, monitor.route_table.routes);
     }
 }
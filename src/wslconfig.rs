@@ -0,0 +1,382 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    path::PathBuf,
+};
+#[cfg(windows)]
+use std::{env, fs, path::Path};
+
+use log::debug;
+
+use crate::config::NetworkingMode;
+
+/// An INI file in the format read by WSL: `[section]` headers, `key = value` lines and `#` or `;`
+/// comments. Section names and keys are not case sensitive, and a later value replaces an earlier one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ini {
+    sections: HashMap<String, HashMap<String, String>>,
+}
+
+impl Ini {
+    pub fn parse(s: &str) -> Ini {
+        let mut ini = Ini::default();
+        let mut section = String::new();
+
+        for line in s.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_ascii_lowercase();
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            ini.sections
+                .entry(section.clone())
+                .or_default()
+                .insert(key.trim().to_ascii_lowercase(), unquote(value));
+        }
+
+        ini
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.sections
+            .get(&section.to_ascii_lowercase())?
+            .get(&key.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// A `true` or `false` value. Other values are ignored, as WSL does.
+    pub fn get_bool(&self, section: &str, key: &str) -> Option<bool> {
+        match self.get(section, key)?.to_ascii_lowercase().as_str() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Removes the quotes around a value, or the comment after an unquoted one.
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+
+    match value.strip_prefix('"').and_then(|v| v.split_once('"')) {
+        Some((quoted, _)) => quoted.to_string(),
+        None => value
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .trim_end()
+            .to_string(),
+    }
+}
+
+/// `networkingMode` of `.wslconfig`. route2wsl can only route through NAT and mirrored networking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WslNetworkingMode {
+    Nat,
+    Mirrored,
+    Bridged,
+    VirtioProxy,
+    None,
+    Other(String),
+}
+
+impl WslNetworkingMode {
+    fn parse(s: &str) -> WslNetworkingMode {
+        match s.to_ascii_lowercase().as_str() {
+            "nat" => WslNetworkingMode::Nat,
+            "mirrored" => WslNetworkingMode::Mirrored,
+            "bridged" => WslNetworkingMode::Bridged,
+            "virtioproxy" => WslNetworkingMode::VirtioProxy,
+            "none" => WslNetworkingMode::None,
+            _ => WslNetworkingMode::Other(s.to_string()),
+        }
+    }
+
+    /// The mode to detect the WSL VM with, or `None` when routes can't be sent to it.
+    pub fn supported(&self) -> Option<NetworkingMode> {
+        match self {
+            WslNetworkingMode::Nat => Some(NetworkingMode::Nat),
+            WslNetworkingMode::Mirrored => Some(NetworkingMode::Mirrored),
+            _ => None,
+        }
+    }
+}
+
+impl Display for WslNetworkingMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WslNetworkingMode::Nat => write!(f, "NAT"),
+            WslNetworkingMode::Mirrored => write!(f, "mirrored"),
+            WslNetworkingMode::Bridged => write!(f, "bridged"),
+            WslNetworkingMode::VirtioProxy => write!(f, "virtioproxy"),
+            WslNetworkingMode::None => write!(f, "none"),
+            WslNetworkingMode::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Keys that WSL once read from `[experimental]` and now only reads from `[wsl2]`.
+const MOVED_TO_WSL2: [&str; 5] = [
+    "networkingMode",
+    "firewall",
+    "dnsTunneling",
+    "autoProxy",
+    "dnsProxy",
+];
+
+/// The settings of a user's `.wslconfig` file that affect routing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WslConfig {
+    /// `[wsl2] networkingMode`. WSL uses NAT when it is not set.
+    pub networking_mode: Option<WslNetworkingMode>,
+    /// `[wsl2] vmIdleTimeout`, the milliseconds the VM keeps running after the last WSL process exits.
+    pub vm_idle_timeout: Option<i64>,
+    /// `[wsl2] firewall`, whether the Hyper-V firewall filters the traffic of the VM.
+    pub firewall: Option<bool>,
+    /// `[wsl2] dnsTunneling`, whether DNS requests of the VM are answered by Windows.
+    pub dns_tunneling: Option<bool>,
+    /// `[wsl2] dnsProxy`, whether the VM uses the NAT gateway as its DNS server.
+    pub dns_proxy: Option<bool>,
+    /// `[wsl2] autoProxy`, whether the VM uses the HTTP proxy of Windows.
+    pub auto_proxy: Option<bool>,
+    /// `[wsl2] localhostForwarding`
+    pub localhost_forwarding: Option<bool>,
+    /// `[experimental] hostAddressLoopback`, whether Windows and the VM can reach each other on
+    /// the addresses of the host in mirrored mode.
+    pub host_address_loopback: Option<bool>,
+    /// Keys found in `[experimental]` that WSL now ignores there.
+    pub misplaced: Vec<String>,
+}
+
+impl WslConfig {
+    /// Parses a `.wslconfig` file.
+    pub fn parse(s: &str) -> WslConfig {
+        let ini = Ini::parse(s);
+
+        WslConfig {
+            networking_mode: ini
+                .get("wsl2", "networkingMode")
+                .map(WslNetworkingMode::parse),
+            vm_idle_timeout: ini
+                .get("wsl2", "vmIdleTimeout")
+                .and_then(|v| v.parse().ok()),
+            firewall: ini.get_bool("wsl2", "firewall"),
+            dns_tunneling: ini.get_bool("wsl2", "dnsTunneling"),
+            dns_proxy: ini.get_bool("wsl2", "dnsProxy"),
+            auto_proxy: ini.get_bool("wsl2", "autoProxy"),
+            localhost_forwarding: ini.get_bool("wsl2", "localhostForwarding"),
+            host_address_loopback: ini.get_bool("experimental", "hostAddressLoopback"),
+            misplaced: MOVED_TO_WSL2
+                .iter()
+                .filter(|key| {
+                    ini.get("experimental", key).is_some() && ini.get("wsl2", key).is_none()
+                })
+                .map(|key| key.to_string())
+                .collect(),
+        }
+    }
+
+    /// Reads a `.wslconfig` file.
+    #[cfg(windows)]
+    pub fn load(path: &Path) -> std::io::Result<WslConfig> {
+        Ok(WslConfig::parse(&fs::read_to_string(path)?))
+    }
+}
+
+/// The settings of a distro's `/etc/wsl.conf` file that affect routing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WslConf {
+    /// `[boot] systemd`
+    pub systemd: Option<bool>,
+    /// `[boot] command`, run as root when the distro starts.
+    pub boot_command: Option<String>,
+    /// `[network] generateResolvConf`. WSL writes `/etc/resolv.conf` unless it is false.
+    pub generate_resolv_conf: Option<bool>,
+    /// `[network] generateHosts`
+    pub generate_hosts: Option<bool>,
+    /// `[network] hostname`
+    pub hostname: Option<String>,
+}
+
+impl WslConf {
+    /// Parses a `wsl.conf` file.
+    pub fn parse(s: &str) -> WslConf {
+        let ini = Ini::parse(s);
+
+        WslConf {
+            systemd: ini.get_bool("boot", "systemd"),
+            boot_command: ini.get("boot", "command").map(str::to_string),
+            generate_resolv_conf: ini.get_bool("network", "generateResolvConf"),
+            generate_hosts: ini.get_bool("network", "generateHosts"),
+            hostname: ini.get("network", "hostname").map(str::to_string),
+        }
+    }
+
+    /// Reads a `wsl.conf` file.
+    #[cfg(windows)]
+    pub fn load(path: &Path) -> std::io::Result<WslConf> {
+        Ok(WslConf::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Where Windows reaches the `wsl.conf` of a running distro.
+    #[cfg(windows)]
+    pub fn distro_path(distro: &str) -> PathBuf {
+        PathBuf::from(format!(r"\\wsl.localhost\{}\etc\wsl.conf", distro))
+    }
+}
+
+/// The `.wslconfig` files of every user profile on the machine.
+#[cfg(windows)]
+pub fn user_config_paths() -> Vec<PathBuf> {
+    // The service runs as SYSTEM, so the profiles are found next to the public profile
+    let profiles_dir = env::var_os("PUBLIC")
+        .map(PathBuf::from)
+        .and_then(|public| public.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from(r"C:\Users"));
+
+    let Ok(entries) = fs::read_dir(&profiles_dir) else {
+        return vec![];
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().join(".wslconfig"))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// The `.wslconfig` files of every user profile that could be read.
+#[cfg(windows)]
+pub fn user_configs() -> Vec<(PathBuf, WslConfig)> {
+    user_config_paths()
+        .into_iter()
+        .filter_map(|path| match WslConfig::load(&path) {
+            Ok(config) => Some((path, config)),
+            Err(e) => {
+                debug!("Failed to read {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Mirrored if any user has turned on mirrored networking, NAT otherwise.
+#[cfg(windows)]
+pub fn detect_networking_mode() -> NetworkingMode {
+    networking_mode_of(&user_configs())
+}
+
+/// Mirrored if any of the `.wslconfig` files turns on mirrored networking, NAT otherwise.
+pub fn networking_mode_of(configs: &[(PathBuf, WslConfig)]) -> NetworkingMode {
+    for (path, config) in configs {
+        match &config.networking_mode {
+            Some(WslNetworkingMode::Mirrored) => {
+                debug!("{} turns on mirrored networking", path.display());
+                return NetworkingMode::Mirrored;
+            }
+            Some(mode) if mode.supported().is_none() => {
+                debug!(
+                    "{} uses {} networking, which can't be routed to",
+                    path.display(),
+                    mode
+                );
+            }
+            _ => {}
+        }
+    }
+
+    NetworkingMode::Nat
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIRRORED: &str = include_str!("../tests/fixtures/wsl/wslconfig-mirrored");
+    const EXPERIMENTAL: &str = include_str!("../tests/fixtures/wsl/wslconfig-experimental");
+    const BRIDGED: &str = include_str!("../tests/fixtures/wsl/wslconfig-bridged");
+    const WSL_CONF: &str = include_str!("../tests/fixtures/wsl/wsl.conf");
+
+    #[test]
+    fn networking_mode_is_read_from_the_wsl2_section() {
+        let config = WslConfig::parse(
+            "[WSL2]\nmemory=8GB\n# networkingMode=nat\nnetworkingmode = Mirrored\n",
+        );
+
+        assert_eq!(config.networking_mode, Some(WslNetworkingMode::Mirrored));
+        assert_eq!(
+            WslConfig::parse("[experimental]\nnetworkingMode=mirrored\n").networking_mode,
+            None
+        );
+    }
+
+    #[test]
+    fn wslconfig_keys_are_parsed() {
+        let config = WslConfig::parse(MIRRORED);
+
+        assert_eq!(
+            config,
+            WslConfig {
+                networking_mode: Some(WslNetworkingMode::Mirrored),
+                vm_idle_timeout: Some(120000),
+                firewall: Some(true),
+                dns_tunneling: Some(true),
+                dns_proxy: None,
+                auto_proxy: Some(false),
+                localhost_forwarding: None,
+                host_address_loopback: Some(true),
+                misplaced: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn keys_left_in_the_experimental_section_are_reported() {
+        let config = WslConfig::parse(EXPERIMENTAL);
+
+        assert_eq!(config.networking_mode, None);
+        assert_eq!(config.misplaced, vec!["networkingMode", "dnsTunneling"]);
+    }
+
+    #[test]
+    fn unsupported_modes_are_kept_and_detected_as_nat() {
+        let config = WslConfig::parse(BRIDGED);
+
+        assert_eq!(config.networking_mode, Some(WslNetworkingMode::Bridged));
+        assert_eq!(
+            networking_mode_of(&[(PathBuf::from(".wslconfig"), config)]),
+            NetworkingMode::Nat
+        );
+        assert_eq!(
+            networking_mode_of(&[
+                (PathBuf::from("a"), WslConfig::parse(BRIDGED)),
+                (PathBuf::from("b"), WslConfig::parse(MIRRORED)),
+            ]),
+            NetworkingMode::Mirrored
+        );
+    }
+
+    #[test]
+    fn wsl_conf_keys_are_parsed() {
+        assert_eq!(
+            WslConf::parse(WSL_CONF),
+            WslConf {
+                systemd: Some(true),
+                boot_command: Some(String::from("ip addr add 192.168.1.20/24 dev eth0")),
+                generate_resolv_conf: Some(false),
+                generate_hosts: None,
+                hostname: Some(String::from("devbox")),
+            }
+        );
+    }
+}